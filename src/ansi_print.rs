@@ -0,0 +1,194 @@
+use std::io::Write;
+
+use image::{Rgb, RgbImage};
+
+use crate::options::AnsiBlocks;
+
+/// Quarter blocks indexed by which quadrants take the foreground colour,
+/// top-left = 1, top-right = 2, bottom-left = 4, bottom-right = 8.
+const QUARTER_BLOCKS: [char; 16] = [
+    ' ', '▘', '▝', '▀', '▖', '▌', '▞', '▛', '▗', '▚', '▐', '▜', '▄', '▙', '▟', '█',
+];
+
+/// Copy the pixels of an RGB24 or ARGB32 image surface out of cairo.
+pub fn image_from_surface(surface: &cairo::ImageSurface) -> anyhow::Result<RgbImage> {
+    let format = surface.format();
+    anyhow::ensure!(
+        format == cairo::Format::Rgb24 || format == cairo::Format::ARgb32,
+        "Unsupported surface format for terminal output: {:?}",
+        format
+    );
+    let width = surface.width() as u32;
+    let height = surface.height() as u32;
+    let stride = surface.stride() as usize;
+
+    let mut image = RgbImage::new(width, height);
+    surface.with_data(|data| {
+        for (y, row) in data.chunks(stride).take(height as usize).enumerate() {
+            for x in 0..width as usize {
+                let px = u32::from_ne_bytes([
+                    row[x * 4],
+                    row[x * 4 + 1],
+                    row[x * 4 + 2],
+                    row[x * 4 + 3],
+                ]);
+                let a = if format == cairo::Format::ARgb32 {
+                    px >> 24
+                } else {
+                    255
+                };
+                // Cairo stores premultiplied alpha.
+                let unpremultiply = |c: u32| if a == 0 { 0 } else { (c * 255 / a) as u8 };
+                image.put_pixel(
+                    x as u32,
+                    y as u32,
+                    Rgb([
+                        unpremultiply((px >> 16) & 0xff),
+                        unpremultiply((px >> 8) & 0xff),
+                        unpremultiply(px & 0xff),
+                    ]),
+                );
+            }
+        }
+    })?;
+    Ok(image)
+}
+
+fn distance(a: &Rgb<u8>, b: &Rgb<u8>) -> u32 {
+    a.0.iter()
+        .zip(b.0.iter())
+        .map(|(&a, &b)| (a as i32 - b as i32).pow(2) as u32)
+        .sum()
+}
+
+fn average(pixels: &[&Rgb<u8>]) -> Rgb<u8> {
+    let mut sum = [0u32; 3];
+    for px in pixels {
+        for (s, &c) in sum.iter_mut().zip(px.0.iter()) {
+            *s += c as u32;
+        }
+    }
+    let n = pixels.len().max(1) as u32;
+    Rgb([(sum[0] / n) as u8, (sum[1] / n) as u8, (sum[2] / n) as u8])
+}
+
+/// Split a 2x2 cell into two colours, returning the quadrant mask of the
+/// foreground along with the foreground and background colours.
+fn quarter_cell(quad: [&Rgb<u8>; 4]) -> (usize, Rgb<u8>, Rgb<u8>) {
+    let mut best = (0, 0, 0);
+    for i in 0..4 {
+        for j in (i + 1)..4 {
+            let d = distance(quad[i], quad[j]);
+            if d > best.0 {
+                best = (d, i, j);
+            }
+        }
+    }
+    if best.0 == 0 {
+        return (0, *quad[0], *quad[0]);
+    }
+
+    let (fg, bg) = (quad[best.1], quad[best.2]);
+    let mut mask = 0;
+    let mut fgs = Vec::with_capacity(4);
+    let mut bgs = Vec::with_capacity(4);
+    for (i, px) in quad.iter().enumerate() {
+        if distance(px, fg) <= distance(px, bg) {
+            mask |= 1 << i;
+            fgs.push(*px);
+        } else {
+            bgs.push(*px);
+        }
+    }
+    (mask, average(&fgs), average(&bgs))
+}
+
+struct AnsiWriter<'a> {
+    out: &'a mut dyn Write,
+    fg: Option<Rgb<u8>>,
+    bg: Option<Rgb<u8>>,
+}
+
+impl<'a> AnsiWriter<'a> {
+    fn cell(&mut self, c: char, fg: Rgb<u8>, bg: Rgb<u8>) -> std::io::Result<()> {
+        if self.fg != Some(fg) {
+            write!(self.out, "\x1b[38;2;{};{};{}m", fg[0], fg[1], fg[2])?;
+            self.fg = Some(fg);
+        }
+        if self.bg != Some(bg) {
+            write!(self.out, "\x1b[48;2;{};{};{}m", bg[0], bg[1], bg[2])?;
+            self.bg = Some(bg);
+        }
+        write!(self.out, "{}", c)
+    }
+
+    fn end_line(&mut self) -> std::io::Result<()> {
+        self.fg = None;
+        self.bg = None;
+        writeln!(self.out, "\x1b[0m")
+    }
+}
+
+/// Print `image` with 24-bit colour block characters, downscaled to fit in
+/// `columns` terminal cells.
+pub fn ansi_print_image(
+    image: &RgbImage,
+    blocks: AnsiBlocks,
+    columns: u32,
+    out: &mut dyn Write,
+) -> std::io::Result<()> {
+    let pixels_per_cell = match blocks {
+        AnsiBlocks::Half => 1,
+        AnsiBlocks::Quarter => 2,
+    };
+    let max_width = (columns * pixels_per_cell).max(1);
+
+    let scaled;
+    let image = if image.width() > max_width {
+        let height = (image.height() as u64 * max_width as u64 / image.width() as u64).max(1);
+        scaled = image::imageops::resize(
+            image,
+            max_width,
+            height as u32,
+            image::imageops::FilterType::Triangle,
+        );
+        &scaled
+    } else {
+        image
+    };
+
+    let (width, height) = image.dimensions();
+    if width == 0 || height == 0 {
+        return Ok(());
+    }
+    // Odd edges repeat the last row or column.
+    let pixel = |x: u32, y: u32| image.get_pixel(x.min(width - 1), y.min(height - 1));
+
+    let mut writer = AnsiWriter {
+        out,
+        fg: None,
+        bg: None,
+    };
+    for y in (0..height).step_by(2) {
+        match blocks {
+            AnsiBlocks::Half => {
+                for x in 0..width {
+                    writer.cell('▀', *pixel(x, y), *pixel(x, y + 1))?;
+                }
+            }
+            AnsiBlocks::Quarter => {
+                for x in (0..width).step_by(2) {
+                    let (mask, fg, bg) = quarter_cell([
+                        pixel(x, y),
+                        pixel(x + 1, y),
+                        pixel(x, y + 1),
+                        pixel(x + 1, y + 1),
+                    ]);
+                    writer.cell(QUARTER_BLOCKS[mask], fg, bg)?;
+                }
+            }
+        }
+        writer.end_line()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,104 @@
+//! Timing statistics for --benchmark.
+
+use std::time::Duration;
+
+/// Durations of repeated shaping runs, with the number of glyphs each
+/// produced.
+#[derive(Debug, Default)]
+pub struct Timings {
+    samples: Vec<Duration>,
+    glyphs: usize,
+}
+
+impl Timings {
+    pub fn push(&mut self, elapsed: Duration, num_glyphs: usize) {
+        self.samples.push(elapsed);
+        self.glyphs += num_glyphs;
+    }
+
+    pub fn extend(&mut self, other: &Timings) {
+        self.samples.extend_from_slice(&other.samples);
+        self.glyphs += other.glyphs;
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn total(&self) -> Duration {
+        self.samples.iter().sum()
+    }
+
+    pub fn min(&self) -> Duration {
+        self.samples.iter().min().copied().unwrap_or_default()
+    }
+
+    pub fn max(&self) -> Duration {
+        self.samples.iter().max().copied().unwrap_or_default()
+    }
+
+    pub fn mean(&self) -> Duration {
+        if self.samples.is_empty() {
+            return Duration::ZERO;
+        }
+        self.total() / self.samples.len() as u32
+    }
+
+    pub fn median(&self) -> Duration {
+        let mut sorted = self.samples.clone();
+        sorted.sort_unstable();
+        match sorted.len() {
+            0 => Duration::ZERO,
+            n if n % 2 == 1 => sorted[n / 2],
+            n => (sorted[n / 2 - 1] + sorted[n / 2]) / 2,
+        }
+    }
+
+    pub fn glyphs_per_second(&self) -> f64 {
+        let secs = self.total().as_secs_f64();
+        if secs > 0. {
+            self.glyphs as f64 / secs
+        } else {
+            0.
+        }
+    }
+
+    /// One-line report, e.g. for a line of input or the whole run.
+    pub fn summary(&self) -> String {
+        format!(
+            "{} iterations, {} glyphs: min {:.2?}, median {:.2?}, mean {:.2?}, max {:.2?}, {:.0} glyphs/s",
+            self.len(),
+            self.glyphs,
+            self.min(),
+            self.median(),
+            self.mean(),
+            self.max(),
+            self.glyphs_per_second()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timings(millis: &[u64]) -> Timings {
+        let mut timings = Timings::default();
+        for &ms in millis {
+            timings.push(Duration::from_millis(ms), 1);
+        }
+        timings
+    }
+
+    #[test]
+    fn median() {
+        assert_eq!(timings(&[]).median(), Duration::ZERO);
+        assert_eq!(timings(&[5]).median(), Duration::from_millis(5));
+        assert_eq!(timings(&[3, 1, 2]).median(), Duration::from_millis(2));
+        assert_eq!(timings(&[4, 1, 3, 2]).median(), Duration::from_micros(2500));
+    }
+}
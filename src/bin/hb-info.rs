@@ -0,0 +1,17 @@
+use clap::Parser;
+
+use hb_util as lib;
+
+use lib::options::{FontOpts, HbInfoOptions};
+
+fn run() -> anyhow::Result<()> {
+    let mut opts = HbInfoOptions::parse();
+    opts.load_font()?;
+    lib::info::info(&opts)
+}
+
+fn main() {
+    if let Err(err) = run() {
+        lib::error::exit(&err);
+    }
+}
@@ -0,0 +1,12 @@
+use hb_util as lib;
+
+use lib::font_text::FontText;
+use lib::output_buffer::OutputBuffer;
+use lib::shape_consumer::ShapeConsumer;
+
+fn main() {
+    let result = FontText::<ShapeConsumer<OutputBuffer>>::new().and_then(|mut driver| driver.run());
+    if let Err(err) = result {
+        lib::error::exit(&err);
+    }
+}
@@ -0,0 +1,20 @@
+use clap::Parser;
+
+use hb_util as lib;
+
+use lib::options::{FontOpts, HbSubsetOptions, TextOpts};
+
+fn run() -> anyhow::Result<()> {
+    let mut opts = HbSubsetOptions::parse();
+    opts.load_font()?;
+    if opts.has_text() {
+        opts.read()?;
+    }
+    lib::subset::subset(&opts)
+}
+
+fn main() {
+    if let Err(err) = run() {
+        lib::error::exit(&err);
+    }
+}
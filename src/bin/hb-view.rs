@@ -6,14 +6,11 @@ use lib::font_text::FontText;
 use lib::shape_consumer::ShapeConsumer;
 use lib::view_cairo::ViewCairo;
 
-#[no_mangle]
-pub static DEFAULT_FONT_SIZE: usize = 256;
-#[no_mangle]
-pub static SUBPIXEL_BITS: usize = 6;
-
 fn main() {
-    let mut driver = FontText::<ShapeConsumer<ViewCairo>>::new();
-    driver.run();
+    let result = FontText::<ShapeConsumer<ViewCairo>>::new().and_then(|mut driver| driver.run());
+    if let Err(err) = result {
+        lib::error::exit(&err);
+    }
     // let options = hb_utils::options::Options::parse();
     // println!("{:?}", options);
 }
@@ -371,7 +371,7 @@ fn create_user_scaled_font(font: *mut ffi::hb_font_t) -> cairo::ScaledFont {
 
 fn create_user_font_face(font: *mut ffi::hb_font_t) -> anyhow::Result<cairo::UserFontFace> {
     let cairo_face = cairo::UserFontFace::create()?;
-    let rcfont = Rc::new(unsafe { hb_util::HbFont::from_raw(font) });
+    let rcfont = Rc::new(unsafe { hb_util::Font::from_raw_borrowed(font) });
     cairo_face.set_user_data(&hb_util::HB_CAIRO_FONT_KEY, rcfont)?;
     cairo_face.set_render_glyph_func(hb_util::render_glyph);
     unsafe {
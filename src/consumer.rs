@@ -3,7 +3,12 @@ use crate::options::{FontOpts, TextOpts};
 pub trait Consumer {
     // type Opts: clap::Parser;
     type Opts: clap::Parser + FontOpts + TextOpts;
-    fn with_options(options: &Self::Opts) -> Self;
-    unsafe fn consume_line(&mut self, options: &Self::Opts) -> anyhow::Result<bool>;
-    unsafe fn finish(&mut self, options: &Self::Opts);
+    /// Act on options that do not need a font, before one is loaded.
+    fn handle_early_options(_options: &Self::Opts) {}
+    fn with_options(options: &Self::Opts) -> anyhow::Result<Self>
+    where
+        Self: Sized;
+    /// Consume the next line, returning false once there are none left.
+    fn consume_line(&mut self, options: &Self::Opts) -> anyhow::Result<bool>;
+    fn finish(&mut self, options: &Self::Opts) -> anyhow::Result<()>;
 }
@@ -0,0 +1,99 @@
+//! Errors the tools report to the user, each with its own exit code so
+//! scripts can tell them apart.
+
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Neither --font-file nor --font-family was given.
+    MissingFont,
+    /// The font file can't be read, isn't a font, or lacks the face asked for.
+    InvalidFontFile {
+        path: String,
+        reason: String,
+    },
+    /// fontconfig has nothing for the requested family.
+    FontNotFound(String),
+    InvalidFeature(String),
+    InvalidVariation(String),
+    InvalidColor(String),
+    UnknownShaper(String),
+    UnknownFontFuncs(String),
+    /// Failure opening or writing the output.
+    Output(std::io::Error),
+    /// Number of input lines that failed to shape or to pass --verify.
+    ShapingFailed(usize),
+}
+
+impl Error {
+    /// Exit status for the tools. 1 is left for unexpected failures and 2 is
+    /// what clap uses for usage errors.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::InvalidFontFile { .. } => 3,
+            Error::FontNotFound(_) => 4,
+            Error::InvalidFeature(_) => 5,
+            Error::InvalidVariation(_) => 6,
+            Error::InvalidColor(_) => 7,
+            Error::UnknownShaper(_) => 8,
+            Error::UnknownFontFuncs(_) => 9,
+            Error::Output(_) => 10,
+            Error::ShapingFailed(_) => 11,
+            Error::MissingFont => 12,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::MissingFont => write!(f, "One of --font-file or --font-family is required"),
+            Error::InvalidFontFile { path, reason } => write!(f, "{}: {}", path, reason),
+            Error::FontNotFound(family) => write!(f, "No font found for family `{}'", family),
+            Error::InvalidFeature(feature) => write!(f, "Invalid feature `{}'", feature),
+            Error::InvalidVariation(variation) => write!(f, "Invalid variation `{}'", variation),
+            Error::InvalidColor(color) => write!(
+                f,
+                "Invalid color `{}'; expected rrggbb or rrggbbaa in hex",
+                color
+            ),
+            Error::UnknownShaper(shaper) => write!(
+                f,
+                "Unknown or unsupported shaper `{}'; supported shapers are: {}",
+                shaper,
+                crate::shape::supported_shapers().join("/")
+            ),
+            Error::UnknownFontFuncs(name) => write!(
+                f,
+                "Unknown font function implementation `{}'; supported values are: {}",
+                name,
+                crate::options::supported_font_funcs_names()
+            ),
+            Error::Output(err) => write!(f, "{}", err),
+            Error::ShapingFailed(lines) => {
+                write!(f, "{} line(s) failed to shape or verify", lines)
+            }
+        }
+    }
+}
+
+// The message of `Output` already includes the I/O error, so it is not
+// reported as the source as well.
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Error {
+        Error::Output(err)
+    }
+}
+
+/// Print `err` and exit with the code of the first `Error` in its chain, or
+/// 1 if there is none.
+pub fn exit(err: &anyhow::Error) -> ! {
+    eprintln!("error: {:#}", err);
+    let code = err
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<Error>())
+        .map_or(1, Error::exit_code);
+    std::process::exit(code)
+}
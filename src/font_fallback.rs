@@ -0,0 +1,280 @@
+use crate::hb::{Buffer, Font};
+use crate::options::{FontOpts, ShapeOptions, ShapeOpts, TextOpts};
+use crate::text_encoding::{byte_to_cluster, cluster_to_byte};
+
+/// Glyphs `start..end` of a shaped buffer, and the font they were shaped with.
+pub struct FontRun {
+    pub font: Font,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl FontRun {
+    pub fn new(font: Font, start: usize, end: usize) -> FontRun {
+        FontRun { font, start, end }
+    }
+}
+
+/// A cluster of the shaped line with a `.notdef` glyph, or neighbouring
+/// clusters that fall back to the same font: glyphs `start..end`, covering
+/// the text between cluster values `first` and `last`.
+struct MissingRun {
+    start: usize,
+    end: usize,
+    first: u32,
+    last: u32,
+}
+
+/// Every cluster of the shaped line in `buffer` that has a `.notdef` glyph,
+/// in glyph order.
+fn missing_runs(buffer: &Buffer, text_end: u32) -> Vec<MissingRun> {
+    let infos = buffer.glyph_infos();
+
+    // A cluster's text ends where the next cluster in logical order starts.
+    let mut starts: Vec<u32> = infos.iter().map(|info| info.cluster).collect();
+    starts.sort_unstable();
+    starts.dedup();
+    let next_start = |cluster: u32| {
+        let i = starts.partition_point(|&start| start <= cluster);
+        starts.get(i).copied().unwrap_or(text_end)
+    };
+
+    let mut runs: Vec<MissingRun> = Vec::new();
+    let mut start = 0;
+    while start < infos.len() {
+        let cluster = infos[start].cluster;
+        let end = start
+            + infos[start..]
+                .iter()
+                .take_while(|info| info.cluster == cluster)
+                .count();
+        if infos[start..end].iter().any(|info| info.codepoint == 0) {
+            runs.push(MissingRun {
+                start,
+                end,
+                first: cluster,
+                last: next_start(cluster),
+            });
+        }
+        start = end;
+    }
+    runs
+}
+
+/// The text of a shaped line and the context it was shaped with.
+struct Line<'a> {
+    text: &'a str,
+    text_before: Option<&'a str>,
+    text_after: Option<&'a str>,
+    cluster_offsets: Option<Vec<usize>>,
+}
+
+/// Shape `line.text[start..end]` with `font` like the line in `buffer`,
+/// using the rest of the line as context and numbering clusters like the
+/// line.
+fn shape_range<O: ShapeOpts>(
+    opts: &O,
+    font: &Font,
+    buffer: &Buffer,
+    line: &Line,
+    start: usize,
+    end: usize,
+) -> anyhow::Result<Buffer> {
+    let text_before = match start {
+        0 => line.text_before,
+        _ => Some(&line.text[..start]),
+    };
+    let text_after = if end == line.text.len() {
+        line.text_after
+    } else {
+        Some(&line.text[end..])
+    };
+
+    let mut range = Buffer::new();
+    opts.populate_buffer(&mut range, &line.text[start..end], text_before, text_after);
+    let base = byte_to_cluster(line.cluster_offsets.as_deref(), start);
+    for info in range.glyph_infos_mut() {
+        info.cluster += base;
+    }
+    // Keep the direction and script of the line rather than guess them from
+    // a part of it.
+    ShapeOptions::copy_buffer_properties(&mut range, buffer);
+
+    opts.shape(font, &line.text[start..end], &mut range)?;
+    Ok(range)
+}
+
+/// Re-shape the clusters of the shaped line in `buffer` that came out as
+/// `.notdef`, each with the first font of the fallback chain covering it, and
+/// splice the results back into `buffer`. Clusters still missing glyphs
+/// after that are tried again with the fonts further down the chain.
+///
+/// `text_before` and `text_after` are the context `text` was shaped with.
+///
+/// Returns the font of every run of glyphs in the final buffer.
+pub fn shape_fallback<O: FontOpts + TextOpts + ShapeOpts>(
+    opts: &O,
+    buffer: &mut Buffer,
+    text: &str,
+    text_before: Option<&str>,
+    text_after: Option<&str>,
+) -> anyhow::Result<Vec<FontRun>> {
+    let line = Line {
+        text,
+        text_before,
+        text_after,
+        cluster_offsets: opts
+            .text_encoding()
+            .cluster_offsets(text, opts.utf8_clusters()),
+    };
+    let to_offset = |cluster| cluster_to_byte(line.cluster_offsets.as_deref(), text.len(), cluster);
+    let text_end = byte_to_cluster(line.cluster_offsets.as_deref(), text.len());
+
+    let mut font_runs = vec![FontRun::new(opts.font(), 0, buffer.len())];
+    loop {
+        // Every pass moves a missing cluster to a font further down the
+        // chain than the one it was shaped with, so this ends.
+        let mut fallbacks: Vec<(MissingRun, Font)> = Vec::new();
+        for cluster in missing_runs(buffer, text_end) {
+            let current = font_at(&font_runs, cluster.start);
+            let (start, end) = (to_offset(cluster.first), to_offset(cluster.last));
+            let font = match opts.fallback_font(&text[start..end], Some(current)) {
+                Some(font) => font,
+                None => continue,
+            };
+            match fallbacks.last_mut() {
+                Some((run, run_font))
+                    if run.end == cluster.start && run_font.as_ptr() == font.as_ptr() =>
+                {
+                    run.end = cluster.end;
+                    run.first = run.first.min(cluster.first);
+                    run.last = run.last.max(cluster.last);
+                }
+                _ => fallbacks.push((cluster, font)),
+            }
+        }
+        if fallbacks.is_empty() {
+            return Ok(font_runs);
+        }
+
+        let mut replacements = Vec::with_capacity(fallbacks.len());
+        for (run, font) in fallbacks {
+            let (start, end) = (to_offset(run.first), to_offset(run.last));
+            let shaped = shape_range(opts, &font, buffer, &line, start, end)?;
+            replacements.push((run, font, shaped));
+        }
+        font_runs = splice(buffer, &font_runs, replacements);
+    }
+}
+
+/// The font glyph `index` was shaped with.
+fn font_at(font_runs: &[FontRun], index: usize) -> &Font {
+    &font_runs
+        .iter()
+        .find(|run| run.start <= index && index < run.end)
+        .expect("font runs cover the buffer")
+        .font
+}
+
+/// Replace the glyphs of each run in `buffer` with the glyphs shaped with
+/// its fallback font, and return the font runs of the result.
+fn splice(
+    buffer: &mut Buffer,
+    font_runs: &[FontRun],
+    replacements: Vec<(MissingRun, Font, Buffer)>,
+) -> Vec<FontRun> {
+    let num_glyphs = buffer.len();
+    let mut merged = Buffer::new();
+    ShapeOptions::copy_buffer_properties(&mut merged, buffer);
+    let mut runs: Vec<FontRun> = Vec::new();
+    let push_run = |runs: &mut Vec<FontRun>, merged: &Buffer, font: &Font, start: usize| {
+        let end = merged.len();
+        match runs.last_mut() {
+            Some(last) if last.font.as_ptr() == font.as_ptr() => last.end = end,
+            _ => runs.push(FontRun::new(font.clone(), start, end)),
+        }
+    };
+    // Glyphs `from..to` of `buffer`, with the fonts they had.
+    let keep = |runs: &mut Vec<FontRun>, merged: &mut Buffer, from: usize, to: usize| {
+        for run in font_runs
+            .iter()
+            .filter(|run| run.start < to && run.end > from)
+        {
+            let start = merged.len();
+            merged.append(buffer, run.start.max(from), run.end.min(to));
+            push_run(runs, merged, &run.font, start);
+        }
+    };
+
+    let mut next = 0;
+    for (run, font, shaped) in replacements {
+        keep(&mut runs, &mut merged, next, run.start);
+        let start = merged.len();
+        merged.append(&shaped, 0, shaped.len());
+        push_run(&mut runs, &merged, &font, start);
+        next = run.end;
+    }
+    keep(&mut runs, &mut merged, next, num_glyphs);
+
+    buffer.clear_contents();
+    ShapeOptions::copy_buffer_properties(buffer, &merged);
+    buffer.append(&merged, 0, merged.len());
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A buffer with one glyph per `(cluster, glyph id)` pair.
+    fn glyphs(glyphs: &[(u32, u32)]) -> Buffer {
+        let mut buffer = Buffer::new();
+        let text = "x".repeat(glyphs.len());
+        buffer.add_utf8(&text, 0, text.len());
+        for (info, &(cluster, codepoint)) in buffer.glyph_infos_mut().iter_mut().zip(glyphs) {
+            info.cluster = cluster;
+            info.codepoint = codepoint;
+        }
+        buffer
+    }
+
+    fn runs(buffer: &Buffer, text_end: u32) -> Vec<(usize, usize, u32, u32)> {
+        missing_runs(buffer, text_end)
+            .iter()
+            .map(|run| (run.start, run.end, run.first, run.last))
+            .collect()
+    }
+
+    #[test]
+    fn missing_runs_none_missing() {
+        let buffer = glyphs(&[(0, 5), (1, 6), (2, 7)]);
+        assert!(runs(&buffer, 3).is_empty());
+    }
+
+    #[test]
+    fn missing_runs_one_per_cluster() {
+        // "a漢字b", with no glyphs for the two ideographs.
+        let buffer = glyphs(&[(0, 5), (1, 0), (4, 0), (7, 6)]);
+        assert_eq!(runs(&buffer, 8), [(1, 2, 1, 4), (2, 3, 4, 7)]);
+    }
+
+    #[test]
+    fn missing_runs_right_to_left() {
+        let buffer = glyphs(&[(7, 6), (4, 0), (1, 0), (0, 5)]);
+        assert_eq!(runs(&buffer, 8), [(1, 2, 4, 7), (2, 3, 1, 4)]);
+    }
+
+    #[test]
+    fn missing_runs_whole_cluster() {
+        // One glyph of a two glyph cluster is missing: the cluster is
+        // shaped again as a whole.
+        let buffer = glyphs(&[(0, 5), (0, 0), (3, 7)]);
+        assert_eq!(runs(&buffer, 4), [(0, 2, 0, 3)]);
+    }
+
+    #[test]
+    fn missing_runs_last_cluster() {
+        let buffer = glyphs(&[(0, 5), (2, 0)]);
+        assert_eq!(runs(&buffer, 6), [(1, 2, 2, 6)]);
+    }
+}
@@ -9,20 +9,19 @@ pub struct FontText<Cons: Consumer> {
 }
 
 impl<Cons: Consumer> FontText<Cons> {
-    pub fn new() -> Self {
+    pub fn new() -> anyhow::Result<Self> {
         let mut opts = Cons::Opts::parse();
-        opts.load_font();
-        opts.read();
-        let cons = Cons::with_options(&opts);
-        Self { opts, cons }
+        Cons::handle_early_options(&opts);
+        opts.load_font()?;
+        opts.read()?;
+        let cons = Cons::with_options(&opts)?;
+        Ok(Self { opts, cons })
     }
 
-    pub fn run(&mut self) {
-        unsafe {
-            while let Ok(true) = self.cons.consume_line(&self.opts) {
-                //
-            }
-            self.cons.finish(&self.opts);
+    pub fn run(&mut self) -> anyhow::Result<()> {
+        while self.cons.consume_line(&self.opts)? {
+            //
         }
+        self.cons.finish(&self.opts)
     }
 }
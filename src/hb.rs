@@ -0,0 +1,406 @@
+//! Reference-counted owners of harfbuzz objects.
+//!
+//! Cloning takes a new reference with `hb_*_reference` and dropping gives it
+//! back with `hb_*_destroy`, so a pointer handed out by `as_ptr` stays valid
+//! as long as one owner is alive. Only objects that do not change once made
+//! can be cloned; a `Buffer` hands out slices of its contents, so it has a
+//! single owner.
+
+use std::ffi::CString;
+use std::mem::MaybeUninit;
+use std::path::Path;
+
+use harfbuzz_sys as ffi;
+
+macro_rules! hb_object {
+    ($(#[$attr:meta])* clone $name:ident, $raw:ty, $reference:path, $destroy:path) => {
+        hb_object!($(#[$attr])* $name, $raw, $reference, $destroy);
+
+        impl Clone for $name {
+            fn clone(&self) -> Self {
+                unsafe { Self($reference(self.0)) }
+            }
+        }
+    };
+    ($(#[$attr:meta])* $name:ident, $raw:ty, $reference:path, $destroy:path) => {
+        $(#[$attr])*
+        pub struct $name(*mut $raw);
+
+        impl $name {
+            /// Take ownership of one reference to `raw`.
+            ///
+            /// # Safety
+            ///
+            /// `raw` must be a valid, non-null object of this type, and the
+            /// caller must own the reference it gives up: it is destroyed
+            /// when the returned value is dropped.
+            pub unsafe fn from_raw(raw: *mut $raw) -> Self {
+                Self(raw)
+            }
+
+            /// Take a new reference to `raw`, leaving the caller's own intact.
+            ///
+            /// # Safety
+            ///
+            /// `raw` must be a valid, non-null object of this type that
+            /// stays alive for the duration of the call.
+            pub unsafe fn from_raw_borrowed(raw: *mut $raw) -> Self {
+                Self($reference(raw))
+            }
+
+            pub fn as_ptr(&self) -> *mut $raw {
+                self.0
+            }
+        }
+
+        impl Drop for $name {
+            fn drop(&mut self) {
+                unsafe { $destroy(self.0) }
+            }
+        }
+
+        impl std::fmt::Debug for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.debug_tuple(stringify!($name)).field(&self.0).finish()
+            }
+        }
+    };
+}
+
+hb_object!(
+    /// A `hb_blob_t`, usually the data of a font file or table.
+    clone Blob,
+    ffi::hb_blob_t,
+    ffi::hb_blob_reference,
+    ffi::hb_blob_destroy
+);
+hb_object!(
+    /// A `hb_face_t`, one face of a font file.
+    clone Face,
+    ffi::hb_face_t,
+    ffi::hb_face_reference,
+    ffi::hb_face_destroy
+);
+hb_object!(
+    /// A `hb_font_t`, a face at a given size and variation.
+    clone Font,
+    ffi::hb_font_t,
+    ffi::hb_font_reference,
+    ffi::hb_font_destroy
+);
+hb_object!(
+    /// A `hb_buffer_t`, holding text before shaping and glyphs after.
+    Buffer,
+    ffi::hb_buffer_t,
+    ffi::hb_buffer_reference,
+    ffi::hb_buffer_destroy
+);
+
+impl Blob {
+    /// Read the whole of `path`, or `None` if it can't be read.
+    pub fn from_file(path: impl AsRef<Path>) -> Option<Blob> {
+        let path = CString::new(path.as_ref().to_str()?).ok()?;
+        let blob = unsafe { ffi::hb_blob_create_from_file_or_fail(path.as_ptr()) };
+        if blob.is_null() {
+            None
+        } else {
+            Some(Blob(blob))
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        unsafe { ffi::hb_blob_get_length(self.0) as usize }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            let mut length = 0;
+            let data = ffi::hb_blob_get_data(self.0, &mut length);
+            if data.is_null() {
+                &[]
+            } else {
+                std::slice::from_raw_parts(data as *const u8, length as usize)
+            }
+        }
+    }
+}
+
+impl Face {
+    pub fn new(blob: &Blob, index: u32) -> Face {
+        unsafe { Face(ffi::hb_face_create(blob.0, index)) }
+    }
+
+    /// Number of faces in `blob`, more than one for collections.
+    pub fn count(blob: &Blob) -> u32 {
+        unsafe { ffi::hb_face_count(blob.0) }
+    }
+
+    /// The data the face was created from.
+    pub fn blob(&self) -> Blob {
+        unsafe { Blob(ffi::hb_face_reference_blob(self.0)) }
+    }
+
+    /// The data of table `tag`, empty if the face has no such table.
+    pub fn table(&self, tag: ffi::hb_tag_t) -> Blob {
+        unsafe { Blob(ffi::hb_face_reference_table(self.0, tag)) }
+    }
+
+    pub fn table_tags(&self) -> Vec<ffi::hb_tag_t> {
+        unsafe {
+            let count =
+                ffi::hb_face_get_table_tags(self.0, 0, std::ptr::null_mut(), std::ptr::null_mut());
+            let mut tags = vec![0; count as usize];
+            let mut len = count;
+            ffi::hb_face_get_table_tags(self.0, 0, &mut len, tags.as_mut_ptr());
+            tags.truncate(len as usize);
+            tags
+        }
+    }
+
+    pub fn index(&self) -> u32 {
+        unsafe { ffi::hb_face_get_index(self.0) }
+    }
+
+    pub fn upem(&self) -> u32 {
+        unsafe { ffi::hb_face_get_upem(self.0) }
+    }
+
+    pub fn glyph_count(&self) -> u32 {
+        unsafe { ffi::hb_face_get_glyph_count(self.0) }
+    }
+}
+
+impl Font {
+    pub fn new(face: &Face) -> Font {
+        unsafe { Font(ffi::hb_font_create(face.0)) }
+    }
+
+    pub fn face(&self) -> Face {
+        unsafe { Face::from_raw_borrowed(ffi::hb_font_get_face(self.0)) }
+    }
+
+    /// The glyph `font` maps `c` to, if any.
+    pub fn nominal_glyph(&self, c: char) -> Option<ffi::hb_codepoint_t> {
+        let mut glyph = 0;
+        let found = unsafe { ffi::hb_font_get_nominal_glyph(self.0, c as _, &mut glyph) };
+        (found != 0).then(|| glyph)
+    }
+}
+
+impl Buffer {
+    pub fn new() -> Buffer {
+        unsafe { Buffer(ffi::hb_buffer_create()) }
+    }
+
+    /// An empty buffer with the same unicode funcs, flags and replacement
+    /// code points as this one.
+    pub fn create_similar(&self) -> Buffer {
+        unsafe { Buffer(ffi::hb_buffer_create_similar(self.0)) }
+    }
+
+    pub fn len(&self) -> usize {
+        unsafe { ffi::hb_buffer_get_length(self.0) as usize }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Truncate the buffer to `length` items.
+    pub fn set_length(&mut self, length: usize) {
+        unsafe {
+            ffi::hb_buffer_set_length(self.0, length as _);
+        }
+    }
+
+    /// Remove the contents and segment properties, keeping flags and
+    /// replacement code points.
+    pub fn clear_contents(&mut self) {
+        unsafe { ffi::hb_buffer_clear_contents(self.0) }
+    }
+
+    /// Add `text[item_offset..item_offset + item_length]` for shaping, with
+    /// the rest of `text` as context.
+    pub fn add_utf8(&mut self, text: &str, item_offset: usize, item_length: usize) {
+        unsafe {
+            ffi::hb_buffer_add_utf8(
+                self.0,
+                text.as_ptr() as _,
+                text.len() as _,
+                item_offset as _,
+                item_length as _,
+            )
+        }
+    }
+
+    /// Like `add_utf8`, with offsets in UTF-16 code units.
+    pub fn add_utf16(&mut self, text: &[u16], item_offset: usize, item_length: usize) {
+        unsafe {
+            ffi::hb_buffer_add_utf16(
+                self.0,
+                text.as_ptr(),
+                text.len() as _,
+                item_offset as _,
+                item_length as _,
+            )
+        }
+    }
+
+    /// Like `add_utf8`, with offsets in code points.
+    pub fn add_utf32(&mut self, text: &[u32], item_offset: usize, item_length: usize) {
+        unsafe {
+            ffi::hb_buffer_add_utf32(
+                self.0,
+                text.as_ptr(),
+                text.len() as _,
+                item_offset as _,
+                item_length as _,
+            )
+        }
+    }
+
+    /// Like `add_utf8`, for ISO-8859-1 text.
+    pub fn add_latin1(&mut self, text: &[u8], item_offset: usize, item_length: usize) {
+        unsafe {
+            ffi::hb_buffer_add_latin1(
+                self.0,
+                text.as_ptr(),
+                text.len() as _,
+                item_offset as _,
+                item_length as _,
+            )
+        }
+    }
+
+    /// Append items `start..end` of `source`.
+    pub fn append(&mut self, source: &Buffer, start: usize, end: usize) {
+        let end = end.min(u32::MAX as usize);
+        unsafe { ffi::hb_buffer_append(self.0, source.0, start as _, end as _) }
+    }
+
+    pub fn content_type(&self) -> ffi::hb_buffer_content_type_t {
+        unsafe { ffi::hb_buffer_get_content_type(self.0) }
+    }
+
+    pub fn direction(&self) -> ffi::hb_direction_t {
+        unsafe { ffi::hb_buffer_get_direction(self.0) }
+    }
+
+    pub fn set_direction(&mut self, direction: ffi::hb_direction_t) {
+        unsafe { ffi::hb_buffer_set_direction(self.0, direction) }
+    }
+
+    pub fn set_script(&mut self, script: ffi::hb_script_t) {
+        unsafe { ffi::hb_buffer_set_script(self.0, script) }
+    }
+
+    pub fn set_language(&mut self, language: ffi::hb_language_t) {
+        unsafe { ffi::hb_buffer_set_language(self.0, language) }
+    }
+
+    pub fn segment_properties(&self) -> ffi::hb_segment_properties_t {
+        unsafe {
+            let mut props = MaybeUninit::zeroed();
+            ffi::hb_buffer_get_segment_properties(self.0, props.as_mut_ptr());
+            props.assume_init()
+        }
+    }
+
+    pub fn set_segment_properties(&mut self, props: &ffi::hb_segment_properties_t) {
+        unsafe { ffi::hb_buffer_set_segment_properties(self.0, props) }
+    }
+
+    pub fn guess_segment_properties(&mut self) {
+        unsafe { ffi::hb_buffer_guess_segment_properties(self.0) }
+    }
+
+    pub fn flags(&self) -> ffi::hb_buffer_flags_t {
+        unsafe { ffi::hb_buffer_get_flags(self.0) }
+    }
+
+    pub fn set_flags(&mut self, flags: ffi::hb_buffer_flags_t) {
+        unsafe { ffi::hb_buffer_set_flags(self.0, flags) }
+    }
+
+    pub fn cluster_level(&self) -> ffi::hb_buffer_cluster_level_t {
+        unsafe { ffi::hb_buffer_get_cluster_level(self.0) }
+    }
+
+    pub fn set_cluster_level(&mut self, cluster_level: ffi::hb_buffer_cluster_level_t) {
+        unsafe { ffi::hb_buffer_set_cluster_level(self.0, cluster_level) }
+    }
+
+    pub fn set_invisible_glyph(&mut self, invisible: ffi::hb_codepoint_t) {
+        unsafe { ffi::hb_buffer_set_invisible_glyph(self.0, invisible) }
+    }
+
+    pub fn normalize_glyphs(&mut self) {
+        unsafe { ffi::hb_buffer_normalize_glyphs(self.0) }
+    }
+
+    /// Compare glyphs with `reference`, returning the `hb_buffer_diff_flags_t`
+    /// of the differences found.
+    pub fn diff(&self, reference: &Buffer, position_fuzz: u32) -> ffi::hb_buffer_diff_flags_t {
+        unsafe { ffi::hb_buffer_diff(self.0, reference.0, u32::MAX, position_fuzz) }
+    }
+
+    pub fn glyph_infos(&self) -> &[ffi::hb_glyph_info_t] {
+        unsafe {
+            let mut length = 0;
+            let infos = ffi::hb_buffer_get_glyph_infos(self.0, &mut length);
+            if infos.is_null() {
+                &[]
+            } else {
+                std::slice::from_raw_parts(infos, length as usize)
+            }
+        }
+    }
+
+    pub fn glyph_infos_mut(&mut self) -> &mut [ffi::hb_glyph_info_t] {
+        unsafe {
+            let mut length = 0;
+            let infos = ffi::hb_buffer_get_glyph_infos(self.0, &mut length);
+            if infos.is_null() {
+                &mut []
+            } else {
+                std::slice::from_raw_parts_mut(infos, length as usize)
+            }
+        }
+    }
+
+    /// Glyph positions, one per glyph info. Before the buffer has been
+    /// shaped they are all zero.
+    pub fn glyph_positions(&self) -> &[ffi::hb_glyph_position_t] {
+        unsafe {
+            let mut length = 0;
+            let positions = ffi::hb_buffer_get_glyph_positions(self.0, &mut length);
+            if positions.is_null() {
+                &[]
+            } else {
+                std::slice::from_raw_parts(positions, length as usize)
+            }
+        }
+    }
+
+    pub fn glyph_positions_mut(&mut self) -> &mut [ffi::hb_glyph_position_t] {
+        unsafe {
+            let mut length = 0;
+            let positions = ffi::hb_buffer_get_glyph_positions(self.0, &mut length);
+            if positions.is_null() {
+                &mut []
+            } else {
+                std::slice::from_raw_parts_mut(positions, length as usize)
+            }
+        }
+    }
+}
+
+impl Default for Buffer {
+    fn default() -> Self {
+        Buffer::new()
+    }
+}
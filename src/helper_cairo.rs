@@ -1,31 +1,29 @@
+use std::cell::RefCell;
+use std::io::Write;
 use std::{mem::MaybeUninit, rc::Rc};
 
 use harfbuzz_sys as ffi;
 
-use crate::options::{FontOptions, OutputAndFormatOptions, OutputFormat, ViewOptions};
-
-pub struct HbFont(*mut ffi::hb_font_t);
-
-impl Drop for HbFont {
-    fn drop(&mut self) {
-        unsafe { ffi::hb_font_destroy(self.0) }
-    }
-}
-
-impl HbFont {
-    pub fn as_ptr(&self) -> *mut ffi::hb_font_t {
-        self.0
-    }
-    pub unsafe fn from_raw(raw: *mut ffi::hb_font_t) -> Self {
-        Self(raw)
-    }
-}
+use crate::error::Error;
+use crate::font_fallback::FontRun;
+use crate::hb::{Buffer, Font};
+use crate::options::{
+    AnsiBlocks, FontOptions, ImageProtocol, OutputAndFormatOptions, OutputFormat, ViewOptions,
+};
+use crate::text_encoding::cluster_to_byte;
 
 pub struct HelperCairoLine {
+    pub num_glyphs: usize,
     pub glyphs: Vec<cairo::Glyph>,
+    /// Scaled x/y advance of each glyph, for annotations.
+    pub advances: Vec<(f64, f64)>,
+    /// Cluster value of each glyph, for annotations.
+    pub glyph_clusters: Vec<u32>,
     pub utf8: String,
     pub text_clusters: Vec<cairo::TextCluster>,
     pub cluster_flags: cairo::TextClusterFlags,
+    /// Font of each run of glyphs, more than one with --font-fallback.
+    pub font_runs: Vec<FontRun>,
 }
 
 impl HelperCairoLine {
@@ -34,79 +32,113 @@ impl HelperCairoLine {
         (glyph.x(), glyph.y())
     }
 
-    pub unsafe fn from_buffer(
-        buffer: *mut ffi::hb_buffer_t,
+    /// `cluster_offsets` maps cluster values to byte offsets into `text`,
+    /// see `TextEncoding::cluster_offsets`; `None` if they are byte offsets.
+    pub fn from_buffer(
+        buffer: &Buffer,
         text: &str,
         scale_bits: i32,
-        utf8_clusters: bool,
+        cluster_offsets: Option<&[usize]>,
+        font_runs: &[FontRun],
     ) -> Self {
-        let num_glyphs = ffi::hb_buffer_get_length(buffer);
-        let hb_glyph = ffi::hb_buffer_get_glyph_infos(buffer, std::ptr::null_mut());
-        let hb_position = ffi::hb_buffer_get_glyph_positions(buffer, std::ptr::null_mut());
+        let hb_glyph = buffer.glyph_infos();
+        let hb_position = buffer.glyph_positions();
+        let num_glyphs = hb_glyph.len();
+
+        let mut glyphs: Vec<cairo::Glyph> = Vec::with_capacity(num_glyphs + 1);
+        let mut advances = Vec::with_capacity(num_glyphs);
+        let mut glyph_clusters = Vec::with_capacity(num_glyphs);
 
-        let mut glyphs: Vec<cairo::ffi::cairo_glyph_t> =
-            Vec::with_capacity(num_glyphs as usize + 1);
+        let is_backward = crate::hb_direction_is_backward(buffer.direction());
 
-        let mut num_clusters = if num_glyphs > 0 { 1 } else { 0 };
-        for i in 1..(num_clusters as isize) {
-            if (*hb_glyph.offset(i)).cluster != (*hb_glyph.offset(i - 1)).cluster {
+        // Cairo's cluster mapping needs cluster values that grow in logical
+        // order. Without them, e.g. with --cluster-level 2, no mapping is
+        // made and the line is drawn with show_glyphs.
+        let monotone = (1..num_glyphs).all(|i| {
+            let (prev, curr) = (hb_glyph[i - 1].cluster, hb_glyph[i].cluster);
+            if is_backward {
+                prev >= curr
+            } else {
+                prev <= curr
+            }
+        });
+        let mut num_clusters = if num_glyphs > 0 && monotone { 1 } else { 0 };
+        for i in 1..num_glyphs {
+            if monotone && hb_glyph[i].cluster != hb_glyph[i - 1].cluster {
                 num_clusters += 1;
             }
         }
-        let mut clusters: Vec<cairo::ffi::cairo_text_cluster_t> =
-            Vec::with_capacity(num_clusters as usize);
-        clusters.resize_with(num_clusters as usize, || cairo::ffi::cairo_text_cluster_t {
-            num_bytes: 0,
-            num_glyphs: 0,
-        });
+        let mut clusters: Vec<cairo::TextCluster> =
+            vec![cairo::TextCluster::new(0, 0); num_clusters];
 
         let mut x = 0.;
         let mut y = 0.;
-        for i in 0..num_glyphs {
-            let glyph = cairo::ffi::cairo_glyph_t {
-                index: (*hb_glyph.offset(i as _)).codepoint as _,
-                x: libm::scalbn((*hb_position).x_offset as f64 + x, scale_bits),
-                y: libm::scalbn(-(*hb_position).y_offset as f64 + y, scale_bits),
-            };
-            x += (*hb_position).x_advance as f64;
-            y += -(*hb_position).y_advance as f64;
-            glyphs.push(glyph.into());
+        for (info, pos) in hb_glyph.iter().zip(hb_position) {
+            glyphs.push(cairo::Glyph::new(
+                info.codepoint as _,
+                libm::scalbn(pos.x_offset as f64 + x, scale_bits),
+                libm::scalbn(-pos.y_offset as f64 + y, scale_bits),
+            ));
+            advances.push((
+                libm::scalbn(pos.x_advance as f64, scale_bits),
+                libm::scalbn(-pos.y_advance as f64, scale_bits),
+            ));
+            glyph_clusters.push(info.cluster);
+            x += pos.x_advance as f64;
+            y += -pos.y_advance as f64;
         }
-        glyphs.push({
-            cairo::ffi::cairo_glyph_t {
-                index: u64::MAX,
-                x: libm::scalbn(x, scale_bits),
-                y: libm::scalbn(y, scale_bits),
-            }
-            .into()
-        });
+        glyphs.push(cairo::Glyph::new(
+            std::os::raw::c_ulong::MAX,
+            libm::scalbn(x, scale_bits),
+            libm::scalbn(y, scale_bits),
+        ));
+
+        let cluster_flags = if is_backward {
+            cairo::TextClusterFlags::Backward
+        } else {
+            cairo::TextClusterFlags::None
+        };
 
         if !clusters.is_empty() {
-            let is_backward = crate::hb_direction_is_backward(ffi::hb_buffer_get_direction(buffer));
-            let cluster_flags = if is_backward {
-                cairo::TextClusterFlags::Backward
+            let mut cluster = 0;
+            let mut start = 0;
+            clusters[cluster].set_num_glyphs(1);
+
+            // Walk the glyphs in logical order, which is reversed for
+            // backward runs, starting a new cluster on every change.
+            let order: Box<dyn Iterator<Item = (usize, usize)>> = if is_backward {
+                Box::new((0..num_glyphs - 1).rev().map(|i| (i, i + 1)))
             } else {
-                cairo::TextClusterFlags::None
+                Box::new((1..num_glyphs).map(|i| (i, i - 1)))
             };
-
-            let cluster = 0;
-            clusters[cluster].num_glyphs += 1;
-
-            if is_backward {
-                for i in (0..((num_glyphs as isize) - 2)).rev() {
-                    if (*hb_glyph.offset(i)).cluster != (*hb_glyph.offset(i + 1)).cluster {
-                        assert!((*hb_glyph.offset(i)).cluster > (*hb_glyph.offset(i + 1)).cluster);
-                        let end = if utf8_clusters {
-                            todo!();
-                        };
-                    }
-                    todo!()
+            for (i, prev) in order {
+                let curr_cluster = hb_glyph[i].cluster;
+                let prev_cluster = hb_glyph[prev].cluster;
+                if curr_cluster != prev_cluster {
+                    let end = cluster_to_byte(cluster_offsets, text.len(), curr_cluster);
+                    clusters[cluster].set_num_bytes((end - start) as i32);
+                    start = end;
+                    cluster += 1;
                 }
-            } else {
-                todo!()
+                let n = clusters[cluster].num_glyphs();
+                clusters[cluster].set_num_glyphs(n + 1);
             }
+            clusters[cluster].set_num_bytes((text.len() - start) as i32);
+        }
+
+        HelperCairoLine {
+            num_glyphs,
+            glyphs,
+            advances,
+            glyph_clusters,
+            utf8: text.to_string(),
+            text_clusters: clusters,
+            cluster_flags,
+            font_runs: font_runs
+                .iter()
+                .map(|run| FontRun::new(run.font.clone(), run.start, run.end))
+                .collect(),
         }
-        todo!()
     }
 }
 
@@ -611,25 +643,93 @@ pub fn render_color_glyph(
     render_glyph(scaled_font, glyph, cr, extents)
 }
 
-fn create_user_font_face(font_opts: &FontOptions) -> anyhow::Result<cairo::UserFontFace> {
+fn create_user_font_face(font: &Font) -> anyhow::Result<cairo::UserFontFace> {
     let cairo_face = cairo::UserFontFace::create()?;
-    let hb_font = font_opts.font();
-    cairo_face.set_user_data(&HB_CAIRO_FONT_KEY, Rc::new(HbFont(hb_font)))?;
+    cairo_face.set_user_data(&HB_CAIRO_FONT_KEY, Rc::new(font.clone()))?;
     cairo_face.set_render_glyph_func(render_glyph);
+    let face = font.face();
     unsafe {
-        let face = ffi::hb_font_get_face(hb_font);
-        if ffi::hb_ot_color_has_png(face) == 1 || ffi::hb_ot_color_has_layers(face) == 1 {
+        if ffi::hb_ot_color_has_png(face.as_ptr()) == 1
+            || ffi::hb_ot_color_has_layers(face.as_ptr()) == 1
+        {
             cairo_face.set_render_color_glyph_func(render_color_glyph);
         }
     }
     Ok(cairo_face)
 }
 
-fn create_ft_font_face(font_opts: &FontOptions) -> anyhow::Result<cairo::FontFace> {
-    todo!()
+#[cfg(feature = "freetype")]
+thread_local! {
+    static FT_LIBRARY: Result<freetype::Library, freetype::Error> = freetype::Library::init();
+}
+
+#[cfg(feature = "freetype")]
+extern "C" {
+    // Not exposed by freetype-sys.
+    fn FT_Set_Var_Design_Coordinates(
+        face: freetype::ffi::FT_Face,
+        num_coords: freetype::ffi::FT_UInt,
+        coords: *mut freetype::ffi::FT_Fixed,
+    ) -> freetype::ffi::FT_Error;
 }
 
+#[cfg(feature = "freetype")]
+fn create_ft_font_face(font_opts: &FontOptions, font: &Font) -> anyhow::Result<cairo::FontFace> {
+    let face = font.face();
+    let data = face.blob().as_bytes().to_vec();
+    let face_index = face.index();
+
+    // Only the main font can be reloaded from its file.
+    let font_file = if font.as_ptr() == font_opts.font().as_ptr() {
+        font_opts.font_file.as_deref().unwrap_or_default()
+    } else {
+        ""
+    };
+    let mut ft_face = FT_LIBRARY.with(|library| {
+        let library = library
+            .as_ref()
+            .map_err(|err| anyhow::anyhow!("Failed to initialize FreeType: {}", err))?;
+        library
+            .new_memory_face(data, face_index as isize)
+            .or_else(|err| {
+                if font_file.is_empty() {
+                    Err(err)
+                } else {
+                    library.new_face(font_file, font_opts.face_index as isize)
+                }
+            })
+            .map_err(|err| anyhow::anyhow!("{}: FreeType failed: {}", font_file, err))
+    })?;
+
+    unsafe {
+        let mut num_coords = 0;
+        let coords = ffi::hb_font_get_var_coords_design(font.as_ptr(), &mut num_coords);
+        if num_coords > 0 {
+            let mut ft_coords: Vec<freetype::ffi::FT_Fixed> =
+                std::slice::from_raw_parts(coords, num_coords as usize)
+                    .iter()
+                    .map(|coord| (coord * 65536.) as _)
+                    .collect();
+            FT_Set_Var_Design_Coordinates(
+                ft_face.raw_mut() as *mut _,
+                num_coords,
+                ft_coords.as_mut_ptr(),
+            );
+        }
+    }
+
+    let cairo_face =
+        cairo::FontFace::create_from_ft_with_flags(&ft_face, font_opts.ft_load_flags as _)?;
+    cairo_face.set_user_data(&HB_CAIRO_FONT_KEY, Rc::new(font.clone()))?;
+    Ok(cairo_face)
+}
+
+/// Whether to draw glyphs with hb-draw rather than FreeType, which is only
+/// available with the `freetype` feature.
 fn use_hb_draw() -> bool {
+    if cfg!(not(feature = "freetype")) {
+        return true;
+    }
     let env = std::env::var("HB_DRAW");
     if let Ok(env) = env.as_ref() {
         match env.as_str() {
@@ -638,14 +738,21 @@ fn use_hb_draw() -> bool {
         }
     } else {
         let cr_version = cairo::Version::new();
-        cr_version.major() >= 1 && cr_version.minor() >= 17 && cr_version.micro() >= 5
+        (cr_version.major(), cr_version.minor(), cr_version.micro()) >= (1, 17, 5)
     }
 }
 
 pub fn create_scaled_font(font_opts: &FontOptions) -> anyhow::Result<cairo::ScaledFont> {
-    let font = unsafe { ffi::hb_font_reference(font_opts.font()) };
+    create_scaled_font_for(font_opts, &font_opts.font())
+}
 
-    let font_size = font_opts.font_size.unwrap_or_default();
+/// Create a scaled font for `font`, which is the main font of `font_opts` or
+/// one of its fallback fonts, at the size set in `font_opts`.
+pub fn create_scaled_font_for(
+    font_opts: &FontOptions,
+    font: &Font,
+) -> anyhow::Result<cairo::ScaledFont> {
+    let font_size = font_opts.font_size();
 
     let ctm = cairo::Matrix::identity();
     let mut font_matrix = cairo::Matrix::default();
@@ -658,22 +765,26 @@ pub fn create_scaled_font(font_opts: &FontOptions) -> anyhow::Result<cairo::Scal
     font_options.set_hint_style(cairo::HintStyle::None);
     font_options.set_hint_metrics(cairo::HintMetrics::Off);
 
+    #[cfg(feature = "freetype")]
     let scaled_font = if use_hb_draw() {
-        let face = create_user_font_face(font_opts)?;
+        let face = create_user_font_face(font)?;
         cairo::ScaledFont::new(&face, &font_matrix, &ctm, &font_options)?
     } else {
-        let face = create_ft_font_face(font_opts)?;
+        let face = create_ft_font_face(font_opts, font)?;
+        cairo::ScaledFont::new(&face, &font_matrix, &ctm, &font_options)?
+    };
+    #[cfg(not(feature = "freetype"))]
+    let scaled_font = {
+        let face = create_user_font_face(font)?;
         cairo::ScaledFont::new(&face, &font_matrix, &ctm, &font_options)?
     };
 
-    scaled_font
-        .set_user_data(&HB_CAIRO_FONT_KEY, Rc::new(HbFont(font)))
-        .unwrap();
+    scaled_font.set_user_data(&HB_CAIRO_FONT_KEY, Rc::new(font.clone()))?;
 
     Ok(scaled_font)
 }
 
-pub static HB_CAIRO_FONT_KEY: cairo::UserDataKey<HbFont> = cairo::UserDataKey::new();
+pub static HB_CAIRO_FONT_KEY: cairo::UserDataKey<Font> = cairo::UserDataKey::new();
 
 pub trait ScaledFontExt {
     fn has_data(&self) -> bool;
@@ -718,16 +829,37 @@ impl ScaledFontExt for cairo::ScaledFont {
 //     }
 // }
 
-enum ImageProtocol {
-    None = 0,
-    Item2 = 1,
-    Kitty = 2,
-}
-
 enum SupportedFormat {
     PNG,
 }
 
+type FinalizeFunc = Box<dyn FnOnce(&cairo::Surface) -> anyhow::Result<()>>;
+
+/// Work to run once drawing is done, e.g. encoding an image surface to PNG.
+struct FinalizeClosure(RefCell<Option<FinalizeFunc>>);
+
+static FINALIZE_CLOSURE_KEY: cairo::UserDataKey<FinalizeClosure> = cairo::UserDataKey::new();
+
+fn set_finalize_closure(surface: &cairo::Surface, func: FinalizeFunc) -> anyhow::Result<()> {
+    surface.set_user_data(
+        &FINALIZE_CLOSURE_KEY,
+        Rc::new(FinalizeClosure(RefCell::new(Some(func)))),
+    )?;
+    Ok(())
+}
+
+/// Parse a `rrggbb` or `rrggbbaa` hex color, optionally prefixed with `#`.
+pub fn parse_color(color: &str) -> Result<(u8, u8, u8, u8), Error> {
+    let invalid = || Error::InvalidColor(color.to_string());
+    let hex = color.strip_prefix('#').unwrap_or(color);
+    if !(hex.len() == 6 || hex.len() == 8) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid());
+    let alpha = if hex.len() == 8 { channel(6)? } else { 255 };
+    Ok((channel(0)?, channel(2)?, channel(4)?, alpha))
+}
+
 pub fn create_cairo_context(
     w: f64,
     h: f64,
@@ -735,43 +867,12 @@ pub fn create_cairo_context(
     out_opts: &OutputAndFormatOptions,
     content: cairo::Content,
 ) -> anyhow::Result<cairo::Context> {
-    let protocol = ImageProtocol::None;
+    let protocol = out_opts.protocol();
 
-    let extension = if let Some(ext) = out_opts.output_format {
-        ext
-    } else {
-        OutputFormat::PNG
-    };
+    let extension = out_opts.format();
 
-    let mut br = 0;
-    let mut bg = 0;
-    let mut bb = 0;
-    let mut ba = 255;
-    let mut color = view_opts.background.as_str();
-    if color.starts_with("#") {
-        color = &color[1..];
-    }
-    br = u8::from_str_radix(&color[0..2], 16).unwrap();
-    bg = u8::from_str_radix(&color[2..4], 16).unwrap();
-    bb = u8::from_str_radix(&color[4..6], 16).unwrap();
-    if color.len() > 6 {
-        ba = u8::from_str_radix(&color[6..8], 16).unwrap();
-    }
-
-    let mut color = view_opts.foreground.as_str();
-    if color.starts_with("#") {
-        color = &color[1..];
-    }
-    let mut fr = 0;
-    let mut fg = 0;
-    let mut fb = 0;
-    let mut fa = 255;
-    fr = u8::from_str_radix(&color[0..2], 16).unwrap();
-    fg = u8::from_str_radix(&color[2..4], 16).unwrap();
-    fb = u8::from_str_radix(&color[4..6], 16).unwrap();
-    if color.len() > 6 {
-        fa = u8::from_str_radix(&color[6..8], 16).unwrap();
-    }
+    let (br, bg, bb, ba) = parse_color(&view_opts.background)?;
+    let (fr, fg, fb, fa) = parse_color(&view_opts.foreground)?;
 
     let mut content = content;
     if content == cairo::Content::Alpha {
@@ -783,20 +884,217 @@ pub fn create_cairo_context(
         content = cairo::Content::ColorAlpha;
     }
 
+    let fp = out_opts.get_file_handle()?;
+
     let surface = match extension {
-        OutputFormat::PNG => {
-            //
-            create_png_surface_for_stream()
+        OutputFormat::PNG => create_png_surface_for_stream(fp, w, h, content, protocol)?,
+        OutputFormat::SVG => {
+            let surface = cairo::SvgSurface::for_stream(w, h, fp)?;
+            finish_stream_on_destroy((*surface).clone())?
+        }
+        OutputFormat::PDF => {
+            let surface = cairo::PdfSurface::for_stream(w, h, fp)?;
+            finish_stream_on_destroy((*surface).clone())?
+        }
+        OutputFormat::PS | OutputFormat::EPS => {
+            let surface = cairo::PsSurface::for_stream(w, h, fp)?;
+            surface.set_eps(matches!(extension, OutputFormat::EPS));
+            finish_stream_on_destroy((*surface).clone())?
+        }
+        OutputFormat::ANSI => {
+            create_ansi_surface_for_stream(fp, w, h, content, out_opts.ansi_blocks)?
         }
-        _ => unimplemented!(),
     };
 
     let cr = cairo::Context::new(&surface)?;
-    // surface.content
 
-    todo!()
+    match surface.content() {
+        cairo::Content::Alpha => {
+            let (br, fr, fa) = (br as f64 / 255., fr as f64 / 255., fa as f64 / 255.);
+            cr.set_operator(cairo::Operator::Source);
+            cr.set_source_rgba(1., 1., 1., br);
+            cr.paint()?;
+            cr.set_source_rgba(1., 1., 1., fr * fa + br * (1. - fa));
+        }
+        _ => {
+            cr.set_operator(cairo::Operator::Source);
+            cr.set_source_rgba(
+                br as f64 / 255.,
+                bg as f64 / 255.,
+                bb as f64 / 255.,
+                ba as f64 / 255.,
+            );
+            cr.paint()?;
+            cr.set_operator(cairo::Operator::Over);
+            cr.set_source_rgba(
+                fr as f64 / 255.,
+                fg as f64 / 255.,
+                fb as f64 / 255.,
+                fa as f64 / 255.,
+            );
+        }
+    }
+
+    Ok(cr)
 }
 
-fn create_png_surface_for_stream() -> cairo::Surface {
-    todo!()
+/// Flushes the drawing of `cr` to its output stream.
+pub fn destroy_cairo_context(cr: cairo::Context) -> anyhow::Result<()> {
+    let surface = cr.target();
+    if let Some(closure) = surface.user_data(&FINALIZE_CLOSURE_KEY) {
+        if let Some(finalize) = closure.0.borrow_mut().take() {
+            finalize(&surface)?;
+        }
+    }
+    cr.status()?;
+    Ok(())
+}
+
+/// Vector surfaces write as they go; finishing them flushes the last page.
+fn finish_stream_on_destroy(surface: cairo::Surface) -> anyhow::Result<cairo::Surface> {
+    set_finalize_closure(
+        &surface,
+        Box::new(|surface| {
+            let stream = surface
+                .finish_output_stream()
+                .map_err(|err| Error::Output(err.error))?;
+            if let Ok(mut fp) = stream.downcast::<Box<dyn Write>>() {
+                fp.flush().map_err(Error::Output)?;
+            }
+            Ok(())
+        }),
+    )?;
+    Ok(surface)
+}
+
+fn create_png_surface_for_stream(
+    mut fp: Box<dyn Write>,
+    width: f64,
+    height: f64,
+    content: cairo::Content,
+    protocol: ImageProtocol,
+) -> anyhow::Result<cairo::Surface> {
+    let w = width.ceil() as i32;
+    let h = height.ceil() as i32;
+
+    let format = match content {
+        cairo::Content::Alpha => cairo::Format::A8,
+        cairo::Content::ColorAlpha => cairo::Format::ARgb32,
+        _ => cairo::Format::Rgb24,
+    };
+    let surface = cairo::ImageSurface::create(format, w, h)?;
+
+    set_finalize_closure(
+        &surface,
+        Box::new(move |surface| {
+            let image = cairo::ImageSurface::try_from(surface.clone())
+                .map_err(|_| anyhow::anyhow!("PNG output requires an image surface"))?;
+            if protocol == ImageProtocol::None {
+                image.write_to_png(&mut fp).map_err(png_error)?;
+            } else {
+                let mut png = Vec::new();
+                image.write_to_png(&mut png).map_err(png_error)?;
+                write_inline_image(&mut fp, protocol, &png).map_err(Error::Output)?;
+            }
+            fp.flush().map_err(Error::Output)?;
+            Ok(())
+        }),
+    )?;
+
+    Ok((*surface).clone())
+}
+
+/// Failing to write the PNG is an output error, failing to encode it is not.
+fn png_error(err: cairo::IoError) -> anyhow::Error {
+    match err {
+        cairo::IoError::Io(err) => Error::Output(err).into(),
+        err => err.into(),
+    }
+}
+
+/// Wrap a PNG in the escape sequences of a terminal inline image protocol.
+fn write_inline_image(
+    fp: &mut dyn Write,
+    protocol: ImageProtocol,
+    png: &[u8],
+) -> std::io::Result<()> {
+    let data = base64::encode(png);
+    match protocol {
+        ImageProtocol::None => fp.write_all(png),
+        ImageProtocol::Iterm2 => {
+            writeln!(
+                fp,
+                "\x1b]1337;File=inline=1;size={}:{}\x07",
+                png.len(),
+                data
+            )
+        }
+        ImageProtocol::Kitty => {
+            // Kitty limits each escape sequence to 4096 bytes of payload.
+            let chunks: Vec<&[u8]> = data.as_bytes().chunks(4096).collect();
+            for (i, chunk) in chunks.iter().enumerate() {
+                let more = if i + 1 < chunks.len() { 1 } else { 0 };
+                if i == 0 {
+                    write!(fp, "\x1b_Gf=100,a=T,m={};", more)?;
+                } else {
+                    write!(fp, "\x1b_Gm={};", more)?;
+                }
+                fp.write_all(chunk)?;
+                write!(fp, "\x1b\\")?;
+            }
+            writeln!(fp)
+        }
+    }
+}
+
+fn create_ansi_surface_for_stream(
+    mut fp: Box<dyn Write>,
+    width: f64,
+    height: f64,
+    content: cairo::Content,
+    blocks: AnsiBlocks,
+) -> anyhow::Result<cairo::Surface> {
+    let w = width.ceil() as i32;
+    let h = height.ceil() as i32;
+
+    // Terminals have no alpha-only mode, so always render in colour.
+    let format = match content {
+        cairo::Content::ColorAlpha => cairo::Format::ARgb32,
+        _ => cairo::Format::Rgb24,
+    };
+    let surface = cairo::ImageSurface::create(format, w, h)?;
+
+    set_finalize_closure(
+        &surface,
+        Box::new(move |surface| {
+            let image = cairo::ImageSurface::try_from(surface.clone())
+                .map_err(|_| anyhow::anyhow!("ANSI output requires an image surface"))?;
+            let image = crate::ansi_print::image_from_surface(&image)?;
+            let (columns, _) = viuer::terminal_size();
+            crate::ansi_print::ansi_print_image(&image, blocks, columns as u32, &mut fp)
+                .and_then(|_| fp.flush())
+                .map_err(Error::Output)?;
+            Ok(())
+        }),
+    )?;
+
+    Ok((*surface).clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_color() {
+        assert_eq!(super::parse_color("#ff000080").unwrap(), (255, 0, 0, 128));
+        assert_eq!(super::parse_color("00Ff00").unwrap(), (0, 255, 0, 255));
+        for color in ["#fff", "", "#", "gggggg", "#ff00008", "+1ff00"] {
+            assert!(
+                matches!(super::parse_color(color), Err(Error::InvalidColor(_))),
+                "{:?}",
+                color
+            );
+        }
+    }
 }
@@ -0,0 +1,648 @@
+use std::ffi::CStr;
+use std::io::Write;
+use std::os::raw::c_char;
+
+use harfbuzz_sys as ffi;
+
+use crate::error::Error;
+use crate::hb::Face;
+use crate::options::{FontOpts, HbInfoOptions, SerializeFormat};
+
+const HB_OT_LAYOUT_DEFAULT_LANGUAGE_INDEX: u32 = 0xFFFF;
+
+const fn tag(bytes: &[u8; 4]) -> ffi::hb_tag_t {
+    u32::from_be_bytes(*bytes)
+}
+
+fn tag_to_string(tag: ffi::hb_tag_t) -> String {
+    String::from_utf8_lossy(&tag.to_be_bytes())
+        .trim_end()
+        .to_string()
+}
+
+pub struct TableInfo {
+    pub tag: String,
+    pub size: u32,
+}
+
+pub struct NameEntry {
+    pub name_id: u32,
+    pub language: String,
+    pub value: String,
+}
+
+/// Metrics of the `OS/2` table, in font units.
+pub struct Os2Metrics {
+    pub version: u16,
+    pub x_avg_char_width: i16,
+    pub weight_class: u16,
+    pub width_class: u16,
+    pub fs_type: u16,
+    pub fs_selection: u16,
+    pub typo_ascender: i16,
+    pub typo_descender: i16,
+    pub typo_line_gap: i16,
+    pub win_ascent: u16,
+    pub win_descent: u16,
+    /// Only present from version 2 on.
+    pub x_height: Option<i16>,
+    pub cap_height: Option<i16>,
+}
+
+/// Metrics of the `hhea` table, in font units.
+pub struct HheaMetrics {
+    pub ascender: i16,
+    pub descender: i16,
+    pub line_gap: i16,
+    pub advance_width_max: u16,
+    pub min_left_side_bearing: i16,
+    pub min_right_side_bearing: i16,
+    pub x_max_extent: i16,
+    pub caret_slope_rise: i16,
+    pub caret_slope_run: i16,
+    pub number_of_h_metrics: u16,
+}
+
+pub struct LanguageInfo {
+    pub tag: String,
+    pub features: Vec<String>,
+}
+
+pub struct ScriptInfo {
+    pub tag: String,
+    pub languages: Vec<LanguageInfo>,
+}
+
+/// Scripts, languages and features of a GSUB or GPOS table.
+pub struct LayoutTable {
+    pub tag: String,
+    pub scripts: Vec<ScriptInfo>,
+}
+
+pub struct AxisInfo {
+    pub tag: String,
+    pub name: String,
+    pub min: f32,
+    pub default: f32,
+    pub max: f32,
+    pub hidden: bool,
+}
+
+pub struct NamedInstance {
+    pub subfamily: String,
+    pub postscript_name: Option<String>,
+    pub coords: Vec<f32>,
+}
+
+/// Everything hb-info reports about a face.
+pub struct FontInfo {
+    pub face_count: u32,
+    pub face_index: u32,
+    pub upem: u32,
+    pub glyph_count: u32,
+    pub tables: Vec<TableInfo>,
+    pub names: Vec<NameEntry>,
+    pub os2: Option<Os2Metrics>,
+    pub hhea: Option<HheaMetrics>,
+    pub layout: Vec<LayoutTable>,
+    pub axes: Vec<AxisInfo>,
+    pub instances: Vec<NamedInstance>,
+}
+
+/// Run a paged harfbuzz getter twice, once for the total count and once to
+/// fill the array.
+unsafe fn collect<T: Copy>(get: impl Fn(u32, *mut u32, *mut T) -> u32) -> Vec<T> {
+    let total = get(0, &mut 0, std::ptr::null_mut());
+    let mut items = vec![std::mem::zeroed(); total as usize];
+    let mut count = total;
+    get(0, &mut count, items.as_mut_ptr());
+    items.truncate(count as usize);
+    items
+}
+
+fn table_data(face: &Face, tag: ffi::hb_tag_t) -> Option<Vec<u8>> {
+    let blob = face.table(tag);
+    if blob.is_empty() {
+        None
+    } else {
+        Some(blob.as_bytes().to_vec())
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_i16(data: &[u8], offset: usize) -> i16 {
+    read_u16(data, offset) as i16
+}
+
+/// Entry `name_id` of the `name` table in `language`, or in the default
+/// language if that is null.
+pub(crate) unsafe fn name_string(
+    face: *mut ffi::hb_face_t,
+    name_id: ffi::hb_ot_name_id_t,
+    language: ffi::hb_language_t,
+) -> Option<String> {
+    let len = ffi::hb_ot_name_get_utf8(face, name_id, language, &mut 0, std::ptr::null_mut());
+    if len == 0 {
+        return None;
+    }
+    let mut buf = vec![0u8; len as usize + 1];
+    let mut size = buf.len() as u32;
+    ffi::hb_ot_name_get_utf8(face, name_id, language, &mut size, buf.as_mut_ptr() as _);
+    buf.truncate(size as usize);
+    Some(String::from_utf8_lossy(&buf).into_owned())
+}
+
+unsafe fn language_string(language: ffi::hb_language_t) -> String {
+    let s = ffi::hb_language_to_string(language);
+    if s.is_null() {
+        String::new()
+    } else {
+        CStr::from_ptr(s as *const c_char)
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+fn tables(face: &Face) -> Vec<TableInfo> {
+    face.table_tags()
+        .into_iter()
+        .map(|tag| TableInfo {
+            tag: tag_to_string(tag),
+            size: face.table(tag).len() as u32,
+        })
+        .collect()
+}
+
+unsafe fn names(face: *mut ffi::hb_face_t) -> Vec<NameEntry> {
+    let mut num_entries = 0;
+    let entries = ffi::hb_ot_name_list_names(face, &mut num_entries);
+    if entries.is_null() {
+        return Vec::new();
+    }
+    std::slice::from_raw_parts(entries, num_entries as usize)
+        .iter()
+        .map(|entry| NameEntry {
+            name_id: entry.name_id,
+            language: language_string(entry.language),
+            value: name_string(face, entry.name_id, entry.language).unwrap_or_default(),
+        })
+        .collect()
+}
+
+fn os2_metrics(face: &Face) -> Option<Os2Metrics> {
+    let data = table_data(face, tag(b"OS/2"))?;
+    if data.len() < 78 {
+        return None;
+    }
+    let version = read_u16(&data, 0);
+    let has_v2 = version >= 2 && data.len() >= 90;
+    Some(Os2Metrics {
+        version,
+        x_avg_char_width: read_i16(&data, 2),
+        weight_class: read_u16(&data, 4),
+        width_class: read_u16(&data, 6),
+        fs_type: read_u16(&data, 8),
+        fs_selection: read_u16(&data, 62),
+        typo_ascender: read_i16(&data, 68),
+        typo_descender: read_i16(&data, 70),
+        typo_line_gap: read_i16(&data, 72),
+        win_ascent: read_u16(&data, 74),
+        win_descent: read_u16(&data, 76),
+        x_height: has_v2.then(|| read_i16(&data, 86)),
+        cap_height: has_v2.then(|| read_i16(&data, 88)),
+    })
+}
+
+fn hhea_metrics(face: &Face) -> Option<HheaMetrics> {
+    let data = table_data(face, tag(b"hhea"))?;
+    if data.len() < 36 {
+        return None;
+    }
+    Some(HheaMetrics {
+        ascender: read_i16(&data, 4),
+        descender: read_i16(&data, 6),
+        line_gap: read_i16(&data, 8),
+        advance_width_max: read_u16(&data, 10),
+        min_left_side_bearing: read_i16(&data, 12),
+        min_right_side_bearing: read_i16(&data, 14),
+        x_max_extent: read_i16(&data, 16),
+        caret_slope_rise: read_i16(&data, 18),
+        caret_slope_run: read_i16(&data, 20),
+        number_of_h_metrics: read_u16(&data, 34),
+    })
+}
+
+unsafe fn language_features(
+    face: *mut ffi::hb_face_t,
+    table_tag: ffi::hb_tag_t,
+    script_index: u32,
+    language_index: u32,
+) -> Vec<String> {
+    collect(|start, count, tags| {
+        ffi::hb_ot_layout_language_get_feature_tags(
+            face,
+            table_tag,
+            script_index,
+            language_index,
+            start,
+            count,
+            tags,
+        )
+    })
+    .into_iter()
+    .map(tag_to_string)
+    .collect()
+}
+
+unsafe fn layout_table(face: *mut ffi::hb_face_t, table_tag: ffi::hb_tag_t) -> LayoutTable {
+    let script_tags = collect(|start, count, tags| {
+        ffi::hb_ot_layout_table_get_script_tags(face, table_tag, start, count, tags)
+    });
+    let scripts = script_tags
+        .into_iter()
+        .enumerate()
+        .map(|(script_index, script_tag)| {
+            let script_index = script_index as u32;
+            let mut languages = vec![LanguageInfo {
+                tag: "dflt".to_string(),
+                features: language_features(
+                    face,
+                    table_tag,
+                    script_index,
+                    HB_OT_LAYOUT_DEFAULT_LANGUAGE_INDEX,
+                ),
+            }];
+            let language_tags = collect(|start, count, tags| {
+                ffi::hb_ot_layout_script_get_language_tags(
+                    face,
+                    table_tag,
+                    script_index,
+                    start,
+                    count,
+                    tags,
+                )
+            });
+            for (language_index, language_tag) in language_tags.into_iter().enumerate() {
+                languages.push(LanguageInfo {
+                    tag: tag_to_string(language_tag),
+                    features: language_features(
+                        face,
+                        table_tag,
+                        script_index,
+                        language_index as u32,
+                    ),
+                });
+            }
+            ScriptInfo {
+                tag: tag_to_string(script_tag),
+                languages,
+            }
+        })
+        .collect();
+    LayoutTable {
+        tag: tag_to_string(table_tag),
+        scripts,
+    }
+}
+
+unsafe fn axes(face: *mut ffi::hb_face_t) -> Vec<AxisInfo> {
+    let infos: Vec<ffi::hb_ot_var_axis_info_t> =
+        collect(|start, count, infos| ffi::hb_ot_var_get_axis_infos(face, start, count, infos));
+    infos
+        .into_iter()
+        .map(|info| AxisInfo {
+            tag: tag_to_string(info.tag),
+            name: name_string(face, info.name_id, std::ptr::null()).unwrap_or_default(),
+            min: info.min_value,
+            default: info.default_value,
+            max: info.max_value,
+            hidden: info.flags & ffi::HB_OT_VAR_AXIS_FLAG_HIDDEN != 0,
+        })
+        .collect()
+}
+
+unsafe fn named_instances(face: *mut ffi::hb_face_t) -> Vec<NamedInstance> {
+    (0..ffi::hb_ot_var_get_named_instance_count(face))
+        .map(|index| {
+            let subfamily_id = ffi::hb_ot_var_named_instance_get_subfamily_name_id(face, index);
+            let postscript_id = ffi::hb_ot_var_named_instance_get_postscript_name_id(face, index);
+            NamedInstance {
+                subfamily: name_string(face, subfamily_id, std::ptr::null()).unwrap_or_default(),
+                postscript_name: name_string(face, postscript_id, std::ptr::null()),
+                coords: collect(|_, count, coords| {
+                    ffi::hb_ot_var_named_instance_get_design_coords(face, index, count, coords)
+                }),
+            }
+        })
+        .collect()
+}
+
+impl FontInfo {
+    /// Gather the report for `face`, which was loaded as `face_index` of its file.
+    pub fn from_face(face: &Face, face_index: u32) -> FontInfo {
+        let raw = face.as_ptr();
+        unsafe {
+            FontInfo {
+                face_count: Face::count(&face.blob()),
+                face_index,
+                upem: face.upem(),
+                glyph_count: face.glyph_count(),
+                tables: tables(face),
+                names: names(raw),
+                os2: os2_metrics(face),
+                hhea: hhea_metrics(face),
+                layout: [tag(b"GSUB"), tag(b"GPOS")]
+                    .into_iter()
+                    .map(|table_tag| layout_table(raw, table_tag))
+                    .collect(),
+                axes: axes(raw),
+                instances: named_instances(raw),
+            }
+        }
+    }
+
+    pub fn write_text(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(out, "Face count: {}", self.face_count)?;
+        writeln!(out, "Face index: {}", self.face_index)?;
+        writeln!(out, "Upem: {}", self.upem)?;
+        writeln!(out, "Glyph count: {}", self.glyph_count)?;
+
+        writeln!(out, "\nTables:")?;
+        for table in self.tables.iter() {
+            writeln!(out, "  {:<4}  {:>10} bytes", table.tag, table.size)?;
+        }
+
+        writeln!(out, "\nNames:")?;
+        for name in self.names.iter() {
+            writeln!(
+                out,
+                "  {:>5}  {:<8} {}",
+                name.name_id, name.language, name.value
+            )?;
+        }
+
+        if let Some(ref os2) = self.os2 {
+            writeln!(out, "\nOS/2 metrics:")?;
+            writeln!(out, "  version:          {}", os2.version)?;
+            writeln!(out, "  xAvgCharWidth:    {}", os2.x_avg_char_width)?;
+            writeln!(out, "  usWeightClass:    {}", os2.weight_class)?;
+            writeln!(out, "  usWidthClass:     {}", os2.width_class)?;
+            writeln!(out, "  fsType:           0x{:04X}", os2.fs_type)?;
+            writeln!(out, "  fsSelection:      0x{:04X}", os2.fs_selection)?;
+            writeln!(out, "  sTypoAscender:    {}", os2.typo_ascender)?;
+            writeln!(out, "  sTypoDescender:   {}", os2.typo_descender)?;
+            writeln!(out, "  sTypoLineGap:     {}", os2.typo_line_gap)?;
+            writeln!(out, "  usWinAscent:      {}", os2.win_ascent)?;
+            writeln!(out, "  usWinDescent:     {}", os2.win_descent)?;
+            if let Some(x_height) = os2.x_height {
+                writeln!(out, "  sxHeight:         {}", x_height)?;
+            }
+            if let Some(cap_height) = os2.cap_height {
+                writeln!(out, "  sCapHeight:       {}", cap_height)?;
+            }
+        }
+
+        if let Some(ref hhea) = self.hhea {
+            writeln!(out, "\nhhea metrics:")?;
+            writeln!(out, "  ascender:            {}", hhea.ascender)?;
+            writeln!(out, "  descender:           {}", hhea.descender)?;
+            writeln!(out, "  lineGap:             {}", hhea.line_gap)?;
+            writeln!(out, "  advanceWidthMax:     {}", hhea.advance_width_max)?;
+            writeln!(out, "  minLeftSideBearing:  {}", hhea.min_left_side_bearing)?;
+            writeln!(
+                out,
+                "  minRightSideBearing: {}",
+                hhea.min_right_side_bearing
+            )?;
+            writeln!(out, "  xMaxExtent:          {}", hhea.x_max_extent)?;
+            writeln!(out, "  caretSlopeRise:      {}", hhea.caret_slope_rise)?;
+            writeln!(out, "  caretSlopeRun:       {}", hhea.caret_slope_run)?;
+            writeln!(out, "  numberOfHMetrics:    {}", hhea.number_of_h_metrics)?;
+        }
+
+        for table in self.layout.iter().filter(|table| !table.scripts.is_empty()) {
+            writeln!(out, "\n{} scripts:", table.tag)?;
+            for script in table.scripts.iter() {
+                writeln!(out, "  {}:", script.tag)?;
+                for language in script.languages.iter() {
+                    writeln!(out, "    {}: {}", language.tag, language.features.join(" "))?;
+                }
+            }
+        }
+
+        if !self.axes.is_empty() {
+            writeln!(out, "\nVariation axes:")?;
+            for axis in self.axes.iter() {
+                writeln!(
+                    out,
+                    "  {:<4}  {} {} {}  {}{}",
+                    axis.tag,
+                    axis.min,
+                    axis.default,
+                    axis.max,
+                    axis.name,
+                    if axis.hidden { " (hidden)" } else { "" }
+                )?;
+            }
+        }
+
+        if !self.instances.is_empty() {
+            writeln!(out, "\nNamed instances:")?;
+            for instance in self.instances.iter() {
+                let coords: Vec<_> = self
+                    .axes
+                    .iter()
+                    .zip(instance.coords.iter())
+                    .map(|(axis, coord)| format!("{}={}", axis.tag, coord))
+                    .collect();
+                write!(out, "  {}: {}", instance.subfamily, coords.join(","))?;
+                if let Some(ref postscript_name) = instance.postscript_name {
+                    write!(out, "  ({})", postscript_name)?;
+                }
+                writeln!(out)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_json(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(out, "{{")?;
+        writeln!(out, "  \"face_count\": {},", self.face_count)?;
+        writeln!(out, "  \"face_index\": {},", self.face_index)?;
+        writeln!(out, "  \"upem\": {},", self.upem)?;
+        writeln!(out, "  \"glyph_count\": {},", self.glyph_count)?;
+
+        let tables: Vec<_> = self
+            .tables
+            .iter()
+            .map(|table| {
+                format!(
+                    "{{\"tag\": {}, \"size\": {}}}",
+                    json_string(&table.tag),
+                    table.size
+                )
+            })
+            .collect();
+        writeln!(out, "  \"tables\": [{}],", tables.join(", "))?;
+
+        let names: Vec<_> = self
+            .names
+            .iter()
+            .map(|name| {
+                format!(
+                    "{{\"name_id\": {}, \"language\": {}, \"value\": {}}}",
+                    name.name_id,
+                    json_string(&name.language),
+                    json_string(&name.value)
+                )
+            })
+            .collect();
+        writeln!(out, "  \"names\": [{}],", names.join(", "))?;
+
+        match self.os2 {
+            Some(ref os2) => writeln!(
+                out,
+                "  \"OS/2\": {{\"version\": {}, \"xAvgCharWidth\": {}, \"usWeightClass\": {}, \
+                 \"usWidthClass\": {}, \"fsType\": {}, \"fsSelection\": {}, \"sTypoAscender\": {}, \
+                 \"sTypoDescender\": {}, \"sTypoLineGap\": {}, \"usWinAscent\": {}, \
+                 \"usWinDescent\": {}, \"sxHeight\": {}, \"sCapHeight\": {}}},",
+                os2.version,
+                os2.x_avg_char_width,
+                os2.weight_class,
+                os2.width_class,
+                os2.fs_type,
+                os2.fs_selection,
+                os2.typo_ascender,
+                os2.typo_descender,
+                os2.typo_line_gap,
+                os2.win_ascent,
+                os2.win_descent,
+                json_option(os2.x_height),
+                json_option(os2.cap_height)
+            )?,
+            None => writeln!(out, "  \"OS/2\": null,")?,
+        }
+
+        match self.hhea {
+            Some(ref hhea) => writeln!(
+                out,
+                "  \"hhea\": {{\"ascender\": {}, \"descender\": {}, \"lineGap\": {}, \
+                 \"advanceWidthMax\": {}, \"minLeftSideBearing\": {}, \"minRightSideBearing\": {}, \
+                 \"xMaxExtent\": {}, \"caretSlopeRise\": {}, \"caretSlopeRun\": {}, \
+                 \"numberOfHMetrics\": {}}},",
+                hhea.ascender,
+                hhea.descender,
+                hhea.line_gap,
+                hhea.advance_width_max,
+                hhea.min_left_side_bearing,
+                hhea.min_right_side_bearing,
+                hhea.x_max_extent,
+                hhea.caret_slope_rise,
+                hhea.caret_slope_run,
+                hhea.number_of_h_metrics
+            )?,
+            None => writeln!(out, "  \"hhea\": null,")?,
+        }
+
+        for table in self.layout.iter() {
+            let scripts: Vec<_> = table
+                .scripts
+                .iter()
+                .map(|script| {
+                    let languages: Vec<_> = script
+                        .languages
+                        .iter()
+                        .map(|language| {
+                            let features: Vec<_> =
+                                language.features.iter().map(|f| json_string(f)).collect();
+                            format!("{}: [{}]", json_string(&language.tag), features.join(", "))
+                        })
+                        .collect();
+                    format!("{}: {{{}}}", json_string(&script.tag), languages.join(", "))
+                })
+                .collect();
+            writeln!(
+                out,
+                "  {}: {{{}}},",
+                json_string(&table.tag),
+                scripts.join(", ")
+            )?;
+        }
+
+        let axes: Vec<_> = self
+            .axes
+            .iter()
+            .map(|axis| {
+                format!(
+                    "{{\"tag\": {}, \"name\": {}, \"min\": {}, \"default\": {}, \"max\": {}, \"hidden\": {}}}",
+                    json_string(&axis.tag),
+                    json_string(&axis.name),
+                    axis.min,
+                    axis.default,
+                    axis.max,
+                    axis.hidden
+                )
+            })
+            .collect();
+        writeln!(out, "  \"axes\": [{}],", axes.join(", "))?;
+
+        let instances: Vec<_> = self
+            .instances
+            .iter()
+            .map(|instance| {
+                let coords: Vec<_> = instance.coords.iter().map(|c| c.to_string()).collect();
+                format!(
+                    "{{\"subfamily\": {}, \"postscript_name\": {}, \"coords\": [{}]}}",
+                    json_string(&instance.subfamily),
+                    instance
+                        .postscript_name
+                        .as_deref()
+                        .map(json_string)
+                        .unwrap_or_else(|| "null".to_string()),
+                    coords.join(", ")
+                )
+            })
+            .collect();
+        writeln!(out, "  \"instances\": [{}]", instances.join(", "))?;
+        writeln!(out, "}}")
+    }
+}
+
+fn json_option<T: ToString>(value: Option<T>) -> String {
+    value.map_or_else(|| "null".to_string(), |v| v.to_string())
+}
+
+fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// Report on the font of `opts` in the requested format.
+pub fn info(opts: &HbInfoOptions) -> anyhow::Result<()> {
+    let font = opts.font();
+    let info = FontInfo::from_face(&font.face(), opts.font_opts.face_index as u32);
+    let mut fp = opts.info.get_file_handle()?;
+    match opts.info.output_format {
+        SerializeFormat::Text => info.write_text(&mut fp),
+        SerializeFormat::Json => info.write_json(&mut fp),
+    }
+    .and_then(|_| fp.flush())
+    .map_err(Error::Output)?;
+    Ok(())
+}
@@ -1,12 +1,21 @@
-#![feature(inherent_associated_types)]
-
+pub mod ansi_print;
 pub mod application;
+pub mod benchmark;
 pub mod consumer;
+pub mod error;
+pub mod font_fallback;
 pub mod font_text;
+pub mod hb;
 pub mod helper_cairo;
+pub mod info;
+pub mod line_wrap;
 pub mod options;
 pub mod output;
+pub mod output_buffer;
+pub mod shape;
 pub mod shape_consumer;
+pub mod subset;
+pub mod text_encoding;
 pub mod view_cairo;
 
 use harfbuzz_sys as ffi;
@@ -16,11 +25,16 @@ pub fn hb_direction_is_vertical(dir: ffi::hb_direction_t) -> bool {
     (dir as ::std::os::raw::c_uint) & !1 == 6
 }
 
+#[inline(always)]
+pub fn hb_direction_is_forward(dir: ffi::hb_direction_t) -> bool {
+    (dir as ::std::os::raw::c_uint) & !2 == 4
+}
+
 #[inline(always)]
 pub fn hb_direction_is_backward(dir: ffi::hb_direction_t) -> bool {
     (dir as ::std::os::raw::c_uint) & !2 == 5
 }
 
-pub use helper_cairo::{
-    render_color_glyph, render_glyph, HbFont, ScaledFontExt, HB_CAIRO_FONT_KEY,
-};
+pub use hb::{Blob, Buffer, Face, Font};
+pub use helper_cairo::{render_color_glyph, render_glyph, ScaledFontExt, HB_CAIRO_FONT_KEY};
+pub use shape::{shape, ClusterLevel, Direction, GlyphRun, ShapeRequest, ShapedGlyph, Shaper};
@@ -0,0 +1,354 @@
+//! Breaking shaped lines of input into lines of a given width, for
+//! --wrap-width.
+//!
+//! Each input line is shaped once, as a paragraph. Break opportunities come
+//! from the Unicode line breaking algorithm (UAX #14), and lines are measured
+//! with the advances of the shaped paragraph. Where harfbuzz flags a break as
+//! safe, the glyphs of a line are cut out of the shaped paragraph; only lines
+//! that start or end at an unsafe break are shaped again.
+
+use std::ops::Range;
+
+use harfbuzz_sys as ffi;
+use unicode_linebreak::{linebreaks, BreakOpportunity};
+
+use crate::font_fallback::{shape_fallback, FontRun};
+use crate::hb::Buffer;
+use crate::options::{FontOpts, ShapeOptions, ShapeOpts, TextOpts};
+use crate::text_encoding::{byte_to_cluster, cluster_to_byte};
+
+/// One line of a wrapped paragraph. Cluster values in `buffer` are relative
+/// to the start of `text`.
+pub struct WrappedLine {
+    pub buffer: Buffer,
+    /// Byte range of the line in the paragraph's text.
+    pub text: Range<usize>,
+    pub font_runs: Vec<FontRun>,
+}
+
+struct Paragraph<'a, O> {
+    opts: &'a O,
+    buffer: &'a Buffer,
+    text: &'a str,
+    font_runs: &'a [FontRun],
+    cluster_offsets: Option<Vec<usize>>,
+    /// Visual index of every glyph, in logical order.
+    logical: Vec<usize>,
+    /// Byte offset of the cluster of every glyph, in logical order.
+    logical_bytes: Vec<usize>,
+    /// Sum of the advances of the glyphs before every glyph, in logical
+    /// order, and of all glyphs at the end.
+    advances: Vec<i64>,
+    /// Whether cluster values grow in logical order, so that a range of text
+    /// maps to a range of glyphs.
+    monotone: bool,
+}
+
+impl<'a, O: FontOpts + TextOpts + ShapeOpts> Paragraph<'a, O> {
+    fn new(opts: &'a O, buffer: &'a Buffer, text: &'a str, font_runs: &'a [FontRun]) -> Self {
+        let cluster_offsets = opts
+            .text_encoding()
+            .cluster_offsets(text, opts.utf8_clusters());
+        let num_glyphs = buffer.len();
+        let logical: Vec<usize> = if crate::hb_direction_is_backward(buffer.direction()) {
+            (0..num_glyphs).rev().collect()
+        } else {
+            (0..num_glyphs).collect()
+        };
+        let infos = buffer.glyph_infos();
+        let logical_bytes: Vec<usize> = logical
+            .iter()
+            .map(|&i| cluster_to_byte(cluster_offsets.as_deref(), text.len(), infos[i].cluster))
+            .collect();
+        let monotone = logical_bytes.windows(2).all(|w| w[0] <= w[1]);
+        let vertical = crate::hb_direction_is_vertical(buffer.direction());
+        let positions = buffer.glyph_positions();
+        let mut advances = Vec::with_capacity(num_glyphs + 1);
+        advances.push(0);
+        for &i in &logical {
+            let advance = if vertical {
+                positions[i].y_advance
+            } else {
+                positions[i].x_advance
+            };
+            advances.push(advances.last().unwrap() + (advance as i64).abs());
+        }
+        Paragraph {
+            opts,
+            buffer,
+            text,
+            font_runs,
+            cluster_offsets,
+            logical,
+            logical_bytes,
+            advances,
+            monotone,
+        }
+    }
+
+    /// Logical index of the first glyph at or after byte `offset`.
+    fn glyph_at(&self, offset: usize) -> usize {
+        if offset == 0 {
+            0
+        } else if offset >= self.text.len() {
+            self.logical.len()
+        } else {
+            self.logical_bytes.partition_point(|&b| b < offset)
+        }
+    }
+
+    /// Whether the shaped paragraph can be cut at byte `offset` without
+    /// shaping the text on either side again.
+    fn is_safe_to_break(&self, offset: usize) -> bool {
+        if offset == 0 || offset == self.text.len() {
+            return true;
+        }
+        let g = self.glyph_at(offset);
+        g < self.logical.len()
+            && self.logical_bytes[g] == offset
+            && self.buffer.glyph_infos()[self.logical[g]].mask & ffi::HB_GLYPH_FLAG_UNSAFE_TO_BREAK
+                == 0
+    }
+
+    /// The line for `text[start..end]`.
+    fn line(&self, start: usize, end: usize) -> anyhow::Result<WrappedLine> {
+        if self.monotone && self.is_safe_to_break(start) && self.is_safe_to_break(end) {
+            Ok(self.cut(start, end))
+        } else {
+            self.reshape(start, end)
+        }
+    }
+
+    /// The line for `text[start..end]` made of the paragraph's glyphs.
+    fn cut(&self, start: usize, end: usize) -> WrappedLine {
+        let num_glyphs = self.logical.len();
+        let (first, last) = (self.glyph_at(start), self.glyph_at(end));
+        let glyphs = if crate::hb_direction_is_backward(self.buffer.direction()) {
+            num_glyphs - last..num_glyphs - first
+        } else {
+            first..last
+        };
+
+        let mut buffer = Buffer::new();
+        ShapeOptions::copy_buffer_properties(&mut buffer, self.buffer);
+        buffer.append(self.buffer, glyphs.start, glyphs.end);
+        let base = byte_to_cluster(self.cluster_offsets.as_deref(), start);
+        for info in buffer.glyph_infos_mut() {
+            info.cluster -= base;
+        }
+
+        let font_runs = self
+            .font_runs
+            .iter()
+            .filter(|run| run.start < glyphs.end && run.end > glyphs.start)
+            .map(|run| {
+                FontRun::new(
+                    run.font.clone(),
+                    run.start.max(glyphs.start) - glyphs.start,
+                    run.end.min(glyphs.end) - glyphs.start,
+                )
+            })
+            .collect();
+
+        WrappedLine {
+            buffer,
+            text: start..end,
+            font_runs,
+        }
+    }
+
+    /// The line for `text[start..end]` shaped on its own, with the rest of
+    /// the paragraph as context.
+    fn reshape(&self, start: usize, end: usize) -> anyhow::Result<WrappedLine> {
+        let text = &self.text[start..end];
+        let text_before = match start {
+            0 => self.opts.text_before(),
+            _ => Some(&self.text[..start]),
+        };
+        let text_after = if end == self.text.len() {
+            self.opts.text_after()
+        } else {
+            Some(&self.text[end..])
+        };
+
+        let mut buffer = Buffer::new();
+        self.opts
+            .populate_buffer(&mut buffer, text, text_before, text_after);
+        // Keep the direction and script of the paragraph rather than guess
+        // them from a part of it.
+        ShapeOptions::copy_buffer_properties(&mut buffer, self.buffer);
+        self.opts.shape(&self.opts.font(), text, &mut buffer)?;
+        let font_runs = shape_fallback(self.opts, &mut buffer, text, text_before, text_after)?;
+
+        Ok(WrappedLine {
+            buffer,
+            text: start..end,
+            font_runs,
+        })
+    }
+
+    /// Advance of the paragraph's glyphs for `text[start..end]`, leaving
+    /// out trailing white space.
+    fn width(&self, start: usize, end: usize) -> i64 {
+        let visible_end = start + self.text[start..end].trim_end().len();
+        if self.monotone {
+            self.advances[self.glyph_at(visible_end)] - self.advances[self.glyph_at(start)]
+        } else {
+            (0..self.logical.len())
+                .filter(|&g| (start..visible_end).contains(&self.logical_bytes[g]))
+                .map(|g| self.advances[g + 1] - self.advances[g])
+                .sum()
+        }
+    }
+}
+
+/// Break the shaped line of input in `buffer` into lines no wider than
+/// `max_width`, in the units of the buffer's positions.
+///
+/// Lines are filled greedily, measured on the shaped paragraph; a line shaped
+/// again at an unsafe break may come out slightly wider or narrower. A line
+/// with no break opportunity that fits is as short as the breaks allow, and
+/// wider than `max_width`.
+pub fn wrap_lines<O: FontOpts + TextOpts + ShapeOpts>(
+    opts: &O,
+    buffer: &Buffer,
+    text: &str,
+    font_runs: &[FontRun],
+    max_width: i64,
+) -> anyhow::Result<Vec<WrappedLine>> {
+    let paragraph = Paragraph::new(opts, buffer, text, font_runs);
+    if text.is_empty()
+        || buffer.content_type() != ffi::HB_BUFFER_CONTENT_TYPE_GLYPHS
+        || paragraph.width(0, text.len()) <= max_width
+    {
+        return Ok(vec![paragraph.cut(0, text.len())]);
+    }
+
+    let breaks: Vec<(usize, BreakOpportunity)> = linebreaks(text).collect();
+    let mut lines = Vec::new();
+    let mut start = 0;
+    let mut next = 0;
+    while start < text.len() {
+        let mut best = None;
+        while let Some(&(end, opportunity)) = breaks.get(next) {
+            if best.is_some() && paragraph.width(start, end) > max_width {
+                break;
+            }
+            best = Some(end);
+            next += 1;
+            if opportunity == BreakOpportunity::Mandatory {
+                break;
+            }
+        }
+        // The last break is at the end of the text, so there is always one
+        // after `start`.
+        let end = best.expect("a break after the start of the line");
+        lines.push(paragraph.line(start, end)?);
+        start = end;
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::hb::{Blob, Face, Font};
+    use crate::options::HbShapeOptions;
+
+    fn options(args: &[&str]) -> HbShapeOptions {
+        HbShapeOptions::try_parse_from(std::iter::once("hb-shape").chain(args.iter().copied()))
+            .unwrap()
+    }
+
+    /// `text` shaped with an empty font, with every glyph 10 units wide.
+    fn shape(opts: &HbShapeOptions, text: &str) -> (Buffer, Vec<FontRun>) {
+        let blob = unsafe { Blob::from_raw(ffi::hb_blob_get_empty()) };
+        let font = Font::new(&Face::new(&blob, 0));
+        let mut buffer = Buffer::new();
+        opts.populate_buffer(&mut buffer, text, None, None);
+        opts.shape(&font, text, &mut buffer).unwrap();
+        for position in buffer.glyph_positions_mut() {
+            position.x_advance = 10;
+        }
+        let font_runs = vec![FontRun::new(font, 0, buffer.len())];
+        (buffer, font_runs)
+    }
+
+    fn wrap(args: &[&str], text: &str, max_width: i64) -> Vec<WrappedLine> {
+        let opts = options(args);
+        let (buffer, font_runs) = shape(&opts, text);
+        wrap_lines(&opts, &buffer, text, &font_runs, max_width).unwrap()
+    }
+
+    fn ranges(lines: &[WrappedLine]) -> Vec<Range<usize>> {
+        lines.iter().map(|line| line.text.clone()).collect()
+    }
+
+    fn clusters(line: &WrappedLine) -> Vec<u32> {
+        line.buffer
+            .glyph_infos()
+            .iter()
+            .map(|info| info.cluster)
+            .collect()
+    }
+
+    #[test]
+    fn paragraph_that_fits() {
+        let lines = wrap(&[], "aa bb cc", 80);
+        assert_eq!(ranges(&lines), [0..8]);
+        assert_eq!(lines[0].buffer.len(), 8);
+    }
+
+    #[test]
+    fn fills_lines_greedily() {
+        // Trailing white space doesn't count, so "aa bb " fits in 50.
+        let lines = wrap(&[], "aa bb cc", 50);
+        assert_eq!(ranges(&lines), [0..6, 6..8]);
+        assert_eq!(clusters(&lines[1]), [0, 1]);
+        let runs: Vec<_> = lines[1]
+            .font_runs
+            .iter()
+            .map(|run| run.start..run.end)
+            .collect();
+        assert_eq!(runs, [0..2]);
+    }
+
+    #[test]
+    fn overlong_word() {
+        let lines = wrap(&[], "aaaa b", 20);
+        assert_eq!(ranges(&lines), [0..5, 5..6]);
+    }
+
+    #[test]
+    fn right_to_left() {
+        let lines = wrap(&["--direction=rtl"], "aa bb cc", 50);
+        assert_eq!(ranges(&lines), [0..6, 6..8]);
+        assert_eq!(clusters(&lines[0]), [5, 4, 3, 2, 1, 0]);
+        assert_eq!(clusters(&lines[1]), [1, 0]);
+    }
+
+    #[test]
+    fn utf8_clusters() {
+        let lines = wrap(&["--utf8-clusters"], "\u{e9}\u{e9} bb", 30);
+        assert_eq!(ranges(&lines), [0..5, 5..7]);
+        assert_eq!(clusters(&lines[0]), [0, 2, 4]);
+        assert_eq!(clusters(&lines[1]), [0, 1]);
+    }
+
+    #[test]
+    fn unsafe_to_break() {
+        let opts = options(&[]);
+        let text = "aa bb cc";
+        let (mut buffer, font_runs) = shape(&opts, text);
+        buffer.glyph_infos_mut()[3].mask |= ffi::HB_GLYPH_FLAG_UNSAFE_TO_BREAK;
+        let paragraph = Paragraph::new(&opts, &buffer, text, &font_runs);
+        assert!(paragraph.is_safe_to_break(0));
+        assert!(!paragraph.is_safe_to_break(3));
+        assert!(paragraph.is_safe_to_break(6));
+        assert!(paragraph.is_safe_to_break(8));
+        assert_eq!(paragraph.width(0, 6), 50);
+        assert_eq!(paragraph.width(3, 8), 50);
+    }
+}
@@ -1,16 +1,23 @@
 use std::cell::RefCell;
 use std::ffi::{CStr, CString};
+use std::io::{BufRead, BufReader};
 use std::mem::MaybeUninit;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 
+use anyhow::Context;
 use clap::{ArgEnum, Args, Parser};
 use harfbuzz_sys as ffi;
-use once_cell::sync::OnceCell;
+use once_cell::sync::Lazy;
 
-use crate::helper_cairo::HbFont;
+use crate::error::Error;
+use crate::hb::{Blob, Buffer, Face, Font};
+use crate::shape::{self, ShapeRequest, Shaper};
+use crate::text_encoding::TextEncoding;
 
-const FONT_SIZE_UPEM: usize = 0x7FFFFFFF;
+/// Default font size meaning the upem of the face, i.e. positions in font units.
+pub const FONT_SIZE_UPEM: usize = 0x7FFFFFFF;
 const FONT_SIZE_NONE: usize = 0;
 
 fn version() -> &'static str {
@@ -40,13 +47,13 @@ impl FromStr for Direction {
     }
 }
 
-impl Direction {
-    fn to_hb(&self) -> ffi::hb_direction_t {
-        match self {
-            Direction::LTR => ffi::HB_DIRECTION_LTR,
-            Direction::RTL => ffi::HB_DIRECTION_RTL,
-            Direction::TTB => ffi::HB_DIRECTION_TTB,
-            Direction::BTT => ffi::HB_DIRECTION_BTT,
+impl From<Direction> for shape::Direction {
+    fn from(direction: Direction) -> shape::Direction {
+        match direction {
+            Direction::LTR => shape::Direction::Ltr,
+            Direction::RTL => shape::Direction::Rtl,
+            Direction::TTB => shape::Direction::Ttb,
+            Direction::BTT => shape::Direction::Btt,
         }
     }
 }
@@ -70,12 +77,12 @@ impl FromStr for ClusterLevel {
     }
 }
 
-impl ClusterLevel {
-    fn to_hb(&self) -> ffi::hb_buffer_cluster_level_t {
-        match self {
-            ClusterLevel::MonotoneGraphemes => ffi::HB_BUFFER_CLUSTER_LEVEL_MONOTONE_GRAPHEMES,
-            ClusterLevel::MonotoneCharacters => ffi::HB_BUFFER_CLUSTER_LEVEL_MONOTONE_CHARACTERS,
-            ClusterLevel::Characters => ffi::HB_BUFFER_CLUSTER_LEVEL_CHARACTERS,
+impl From<ClusterLevel> for shape::ClusterLevel {
+    fn from(cluster_level: ClusterLevel) -> shape::ClusterLevel {
+        match cluster_level {
+            ClusterLevel::MonotoneGraphemes => shape::ClusterLevel::MonotoneGraphemes,
+            ClusterLevel::MonotoneCharacters => shape::ClusterLevel::MonotoneCharacters,
+            ClusterLevel::Characters => shape::ClusterLevel::Characters,
         }
     }
 }
@@ -108,15 +115,6 @@ pub struct FontSize {
     pub y: f32,
 }
 
-impl Default for FontSize {
-    fn default() -> Self {
-        Self {
-            x: unsafe { DEFAULT_FONT_SIZE } as f32,
-            y: unsafe { DEFAULT_FONT_SIZE } as f32,
-        }
-    }
-}
-
 #[derive(Clone, Copy, Debug)]
 pub struct FontPpem {
     pub x: u32,
@@ -129,36 +127,66 @@ impl Default for FontPpem {
     }
 }
 
-extern "C" {
-    #[no_mangle]
-    static SUBPIXEL_BITS: i32;
-    #[no_mangle]
-    static DEFAULT_FONT_SIZE: usize;
-}
-
 type FnSetFontFuncs = unsafe extern "C" fn(*mut ffi::hb_font_t);
 struct SetFontFuncs {
     name: &'static str,
     fnptr: FnSetFontFuncs,
 }
 
+/// Font functions implementations, the first one being the default.
 static SUPPORTED_FONT_FUNCS: &'static [SetFontFuncs] = &[
     SetFontFuncs {
         name: "ot",
         fnptr: ffi::hb_ot_font_set_funcs,
     },
-    #[cfg(features = "freetype")]
+    #[cfg(feature = "freetype")]
     SetFontFuncs {
         name: "ft",
         fnptr: ffi::hb_ft_font_set_funcs,
     },
 ];
 
+pub(crate) fn supported_font_funcs_names() -> String {
+    SUPPORTED_FONT_FUNCS
+        .iter()
+        .map(|font_funcs| font_funcs.name)
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn font_funcs_help() -> &'static str {
+    static HELP: Lazy<String> = Lazy::new(|| {
+        format!(
+            "Set font functions implementation to use (default: {})\n\n\
+             Supported font function implementations are: {}",
+            SUPPORTED_FONT_FUNCS[0].name,
+            supported_font_funcs_names()
+        )
+    });
+    HELP.as_str()
+}
+
 #[derive(Debug, Args)]
 pub struct FontOptions {
     /// Set font file-name
+    #[clap(long, conflicts_with = "font-family")]
+    pub font_file: Option<String>,
+
+    /// Set font family-name, looked up through fontconfig instead of --font-file
     #[clap(long)]
-    pub font_file: String,
+    pub font_family: Option<String>,
+
+    /// Set font style-name to look up, eg. "Bold Italic"
+    #[clap(long, requires = "font-family")]
+    pub font_style: Option<String>,
+
+    /// Set font weight to look up, 1 to 1000 or a name like bold
+    #[clap(long, requires = "font-family", parse(try_from_str = parse_font_weight))]
+    pub font_weight: Option<u32>,
+
+    /// Set font width to look up, percentage of normal or a name like condensed
+    #[clap(long, requires = "font-family", parse(try_from_str = parse_font_width))]
+    pub font_width: Option<u32>,
 
     /// Set face index (default: 0)
     #[clap(long, default_value_t = 0)]
@@ -181,10 +209,7 @@ pub struct FontOptions {
     #[clap(long, default_value_t = 0.)]
     pub slant: f32,
 
-    /// Set font functions implementation to use (default: ft)
-    ///
-    /// Supported font function implementations are: ft/ot
-    #[clap(long)]
+    #[clap(long, help = font_funcs_help())]
     pub font_funcs: Option<String>,
 
     /// Set FreeType load-flags (default: 2)
@@ -205,127 +230,278 @@ pub struct FontOptions {
     #[clap(long, verbatim_doc_comment)]
     pub variations: Vec<String>,
 
+    /// Fall back to other fonts from fontconfig for characters the font lacks
+    #[clap(long)]
+    pub font_fallback: bool,
+
+    #[clap(skip)]
+    subpixel_bits: i32,
+
     #[clap(skip)]
-    font: RefCell<Option<FontCache>>,
+    font: RefCell<Option<Font>>,
+
+    #[clap(skip)]
+    fallback_fonts: Vec<FallbackFont>,
 }
 
-#[derive(Clone, Debug)]
-struct FontCache {
-    blob: *mut ffi::hb_blob_t,
-    face: *mut ffi::hb_face_t,
-    font: *mut ffi::hb_font_t,
+/// A font of the fallback chain, only loaded once it is needed.
+#[derive(Debug)]
+struct FallbackFont {
+    file: String,
+    index: usize,
+    font: once_cell::unsync::OnceCell<Option<Font>>,
 }
 
-impl Default for FontCache {
-    fn default() -> Self {
-        FontCache {
-            blob: std::ptr::null_mut(),
-            face: std::ptr::null_mut(),
-            font: std::ptr::null_mut(),
-        }
+impl FontOptions {
+    /// The font size, resolved by `load_font`.
+    pub fn font_size(&self) -> FontSize {
+        self.font_size
+            .expect("font size is resolved when loading the font")
+    }
+
+    pub fn font(&self) -> Font {
+        self.font.borrow().as_ref().unwrap().clone()
     }
-}
 
-impl Drop for FontCache {
-    fn drop(&mut self) {
-        unsafe {
-            ffi::hb_font_destroy(self.font);
-            ffi::hb_face_destroy(self.face);
-            ffi::hb_blob_destroy(self.blob);
+    /// The first font of the fallback chain after `after` that has a glyph
+    /// for every character of `text`, if --font-fallback was given. The
+    /// search starts at the top of the chain if `after` is not in it, like
+    /// the main font.
+    pub fn fallback_font(&self, text: &str, after: Option<&Font>) -> Option<Font> {
+        let skip = after
+            .and_then(|after| {
+                self.fallback_fonts.iter().position(|fallback| {
+                    matches!(fallback.font.get(), Some(Some(font)) if font.as_ptr() == after.as_ptr())
+                })
+            })
+            .map_or(0, |i| i + 1);
+        self.fallback_fonts[skip..].iter().find_map(|fallback| {
+            let font = fallback
+                .font
+                .get_or_init(|| self.create_font(&fallback.file, fallback.index))
+                .as_ref()?;
+            let covers = text
+                .chars()
+                .filter(|&c| !is_ignorable_for_coverage(c))
+                .all(|c| font.nominal_glyph(c).is_some());
+            covers.then(|| font.clone())
+        })
+    }
+
+    /// The fontconfig matches for `family` and the --font-style,
+    /// --font-weight and --font-width options, best first.
+    fn sort_fonts(&self, family: &str) -> anyhow::Result<Vec<FontMatch>> {
+        let fc = fontconfig::Fontconfig::new()
+            .ok_or_else(|| anyhow::anyhow!("Failed to initialize fontconfig"))?;
+        let mut pattern = fontconfig::Pattern::new(&fc);
+        pattern.add_string(&CString::new("family")?, &CString::new(family)?);
+        if let Some(ref style) = self.font_style {
+            pattern.add_string(&CString::new("style")?, &CString::new(style.as_str())?);
+        }
+        if let Some(weight) = self.font_weight {
+            pattern.add_integer(&CString::new("weight")?, fc_weight_from_opentype(weight));
         }
+        if let Some(width) = self.font_width {
+            pattern.add_integer(&CString::new("width")?, width as i32);
+        }
+
+        let family_key = CString::new("family")?;
+        let fonts = pattern.font_sort();
+        Ok(fonts
+            .iter()
+            .filter_map(|font| {
+                Some(FontMatch {
+                    file: font.filename()?.to_string(),
+                    index: font.face_index().unwrap_or(0).max(0) as usize,
+                    family: font.get_string(&family_key).map(str::to_string),
+                })
+            })
+            .collect())
     }
-}
 
-impl FontOptions {
-    pub fn font(&self) -> *mut ffi::hb_font_t {
-        self.font.borrow().as_ref().unwrap().font
+    /// Find the file and face index of the best fontconfig match for
+    /// --font-family and friends.
+    ///
+    /// fontconfig always ends its list with default fonts, so a match is only
+    /// taken if it is of the requested family, or the family is generic.
+    fn match_font(&self, family: &str) -> anyhow::Result<(String, usize)> {
+        let font = self.sort_fonts(family)?.into_iter().next().filter(|font| {
+            is_generic_family(family)
+                || font
+                    .family
+                    .as_deref()
+                    .map_or(false, |matched| same_family(matched, family))
+        });
+        let font = font.ok_or_else(|| Error::FontNotFound(family.to_string()))?;
+        Ok((font.file, font.index))
     }
 
-    fn load_font(&mut self) {
-        assert!(
-            PathBuf::from(&self.font_file).exists(),
-            "{}: Failed reading file",
-            self.font_file
-        );
-        let cache = unsafe {
-            let cstr = CString::new(self.font_file.clone()).unwrap();
-            let blob = ffi::hb_blob_create_from_file_or_fail(cstr.as_ptr());
-            let face = ffi::hb_face_create(blob, self.face_index as _);
-            let font = ffi::hb_font_create(face);
-
-            let font_size = self.font_size.unwrap_or_default();
-            let font_size_x = if font_size.x == FONT_SIZE_UPEM as f32 {
-                ffi::hb_face_get_upem(face) as f32
-            } else {
-                font_size.x
-            };
-            let font_size_y = if font_size.y == FONT_SIZE_UPEM as f32 {
-                ffi::hb_face_get_upem(face) as f32
-            } else {
-                font_size.y
-            };
+    /// Collect the fallback chain for the loaded font, which is the fontconfig
+    /// sort order of its family without the font itself.
+    fn fallback_chain(&self) -> anyhow::Result<Vec<FallbackFont>> {
+        let family = match self.font_family {
+            Some(ref family) => family.clone(),
+            None => {
+                face_family_name(&self.font().face()).unwrap_or_else(|| "sans-serif".to_string())
+            }
+        };
+        let font_file = self.font_file.as_deref().unwrap_or_default();
+        Ok(self
+            .sort_fonts(&family)?
+            .into_iter()
+            .filter(|font| !(font.file == font_file && font.index == self.face_index))
+            .map(|font| FallbackFont {
+                file: font.file,
+                index: font.index,
+                font: Default::default(),
+            })
+            .collect())
+    }
 
-            let _ = self.font_size.replace(FontSize {
-                x: font_size_x,
-                y: font_size_y,
-            });
+    /// Load face `index` of `file` and set it up like the main font, at the
+    /// size of the main font.
+    fn create_font(&self, file: &str, index: usize) -> Option<Font> {
+        let blob = Blob::from_file(file)?;
+        let font = Font::new(&Face::new(&blob, index as _));
+        unsafe { self.setup_font(font.as_ptr()) }.ok()?;
+        Some(font)
+    }
 
-            let ppem = self.ppem.unwrap_or_default();
-            ffi::hb_font_set_ppem(font, ppem.x, ppem.y);
-            ffi::hb_font_set_ptem(font, self.ptem);
+    unsafe fn setup_font(&self, font: *mut ffi::hb_font_t) -> Result<(), Error> {
+        let ppem = self.ppem.unwrap_or_default();
+        ffi::hb_font_set_ppem(font, ppem.x, ppem.y);
+        ffi::hb_font_set_ptem(font, self.ptem);
 
-            ffi::hb_font_set_synthetic_slant(font, self.slant);
+        ffi::hb_font_set_synthetic_slant(font, self.slant);
 
-            let subpixel_bits = SUBPIXEL_BITS;
-            let scale_x: f32 = libm::scalbnf(font_size_x, subpixel_bits);
-            let scale_y: f32 = libm::scalbnf(font_size_y, subpixel_bits);
-            ffi::hb_font_set_scale(font, scale_x as i32, scale_y as i32);
+        let font_size = self.font_size();
+        let scale_x: f32 = libm::scalbnf(font_size.x, self.subpixel_bits);
+        let scale_y: f32 = libm::scalbnf(font_size.y, self.subpixel_bits);
+        ffi::hb_font_set_scale(font, scale_x as i32, scale_y as i32);
 
-            let variations: Vec<_> = self
-                .variations
-                .iter()
-                .map(|var| {
-                    let mut variation: MaybeUninit<ffi::hb_variation_t> = MaybeUninit::zeroed();
-                    let is_ok = ffi::hb_variation_from_string(
-                        var.as_ptr() as _,
-                        var.len() as _,
-                        variation.as_mut_ptr(),
-                    );
-                    assert_eq!(is_ok, 1);
-                    variation.assume_init()
-                })
-                .collect();
-            ffi::hb_font_set_variations(font, variations.as_ptr(), self.variations.len() as _);
-
-            let set_font_funcs = if let Some(ref font_funcs_name) = self.font_funcs {
-                let mut set_font_funcs: Option<FnSetFontFuncs> = None;
-                for font_funcs in SUPPORTED_FONT_FUNCS.iter() {
-                    if font_funcs.name == font_funcs_name {
-                        set_font_funcs.replace(font_funcs.fnptr);
-                        break;
-                    }
-                }
-                if set_font_funcs.is_none() {
-                    panic!("")
+        let variations = self
+            .variations
+            .iter()
+            .map(|var| {
+                let mut variation: MaybeUninit<ffi::hb_variation_t> = MaybeUninit::zeroed();
+                let is_ok = ffi::hb_variation_from_string(
+                    var.as_ptr() as _,
+                    var.len() as _,
+                    variation.as_mut_ptr(),
+                );
+                if is_ok == 0 {
+                    return Err(Error::InvalidVariation(var.clone()));
                 }
-                set_font_funcs.unwrap()
-            } else {
-                ffi::hb_ot_font_set_funcs
-            };
-            set_font_funcs(font);
+                Ok(variation.assume_init())
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        ffi::hb_font_set_variations(font, variations.as_ptr(), variations.len() as _);
+
+        let set_font_funcs: FnSetFontFuncs = if let Some(ref font_funcs_name) = self.font_funcs {
+            SUPPORTED_FONT_FUNCS
+                .iter()
+                .find(|font_funcs| font_funcs.name == font_funcs_name)
+                .map(|font_funcs| font_funcs.fnptr)
+                .ok_or_else(|| Error::UnknownFontFuncs(font_funcs_name.clone()))?
+        } else {
+            SUPPORTED_FONT_FUNCS[0].fnptr
+        };
+        set_font_funcs(font);
 
-            #[cfg(features = "freetype")]
-            ffi::hb_ft_font_set_load_flags(font, self.ft_load_flags);
+        #[cfg(feature = "freetype")]
+        ffi::hb_ft_font_set_load_flags(font, self.ft_load_flags as _);
+
+        Ok(())
+    }
 
-            // TODO: sub_font
+    /// Load the font, scaled to `default_font_size` unless --font-size was
+    /// given, with `subpixel_bits` bits of precision below a pixel.
+    fn load_font(&mut self, default_font_size: usize, subpixel_bits: i32) -> anyhow::Result<()> {
+        // Not required by clap, so that options like --list-shapers work without a font.
+        let font_file = match (self.font_file.clone(), self.font_family.clone()) {
+            (Some(font_file), _) => font_file,
+            (None, Some(family)) => {
+                let (font_file, face_index) = self.match_font(&family)?;
+                self.face_index = face_index;
+                self.font_file = Some(font_file.clone());
+                font_file
+            }
+            (None, None) => return Err(Error::MissingFont.into()),
+        };
+        let invalid = |reason: String| Error::InvalidFontFile {
+            path: font_file.clone(),
+            reason,
+        };
+        let blob =
+            Blob::from_file(&font_file).ok_or_else(|| invalid("Failed reading file".into()))?;
+        let face_count = Face::count(&blob);
+        if face_count == 0 {
+            return Err(invalid("Not a font file".into()).into());
+        }
+        if self.face_index >= face_count as usize {
+            return Err(invalid(format!(
+                "No face at index {}; the file has {}",
+                self.face_index, face_count
+            ))
+            .into());
+        }
+        let face = Face::new(&blob, self.face_index as _);
+        let font = Font::new(&face);
 
-            FontCache { blob, face, font }
+        self.subpixel_bits = subpixel_bits;
+        let font_size = self.font_size.unwrap_or(FontSize {
+            x: default_font_size as f32,
+            y: default_font_size as f32,
+        });
+        let font_size_x = if font_size.x == FONT_SIZE_UPEM as f32 {
+            face.upem() as f32
+        } else {
+            font_size.x
+        };
+        let font_size_y = if font_size.y == FONT_SIZE_UPEM as f32 {
+            face.upem() as f32
+        } else {
+            font_size.y
         };
 
-        self.font.replace(Some(cache));
+        let _ = self.font_size.replace(FontSize {
+            x: font_size_x,
+            y: font_size_y,
+        });
+
+        unsafe { self.setup_font(font.as_ptr()) }?;
+
+        // TODO: sub_font
+
+        self.font.replace(Some(font));
+
+        if self.font_fallback {
+            self.fallback_fonts = self.fallback_chain()?;
+        }
+        Ok(())
     }
 }
 
+/// Characters a font does not need its own glyph for to cover a piece of text.
+fn is_ignorable_for_coverage(c: char) -> bool {
+    c.is_whitespace()
+        || c.is_control()
+        || matches!(
+            c,
+            '\u{200B}'..='\u{200F}'
+                | '\u{2060}'..='\u{206F}'
+                | '\u{FE00}'..='\u{FE0F}'
+                | '\u{E0000}'..='\u{E0FFF}'
+        )
+}
+
+/// The typographic family name of `face`, or its legacy family name.
+fn face_family_name(face: &Face) -> Option<String> {
+    [16, 1].iter().find_map(|&name_id| unsafe {
+        crate::info::name_string(face.as_ptr(), name_id, std::ptr::null())
+    })
+}
+
 pub fn parse_font_size(arg: &str) -> anyhow::Result<FontSize> {
     if arg == "upem" {
         return Ok(FontSize {
@@ -353,6 +529,124 @@ pub fn parse_font_size(arg: &str) -> anyhow::Result<FontSize> {
     }
 }
 
+/// A font fontconfig found for a pattern.
+struct FontMatch {
+    file: String,
+    index: usize,
+    family: Option<String>,
+}
+
+/// Whether `family` is one of the generic families fontconfig resolves
+/// through its aliases to some installed family.
+fn is_generic_family(family: &str) -> bool {
+    const GENERIC: [&str; 10] = [
+        "sans-serif",
+        "sans",
+        "serif",
+        "monospace",
+        "mono",
+        "cursive",
+        "fantasy",
+        "system-ui",
+        "emoji",
+        "math",
+    ];
+    GENERIC
+        .iter()
+        .any(|generic| generic.eq_ignore_ascii_case(family.trim()))
+}
+
+/// Whether two family names are the same to fontconfig, which ignores case
+/// and spaces.
+fn same_family(a: &str, b: &str) -> bool {
+    let key = |name: &str| -> String {
+        name.chars()
+            .filter(|c| !c.is_whitespace())
+            .flat_map(char::to_lowercase)
+            .collect()
+    };
+    key(a) == key(b)
+}
+
+/// OpenType weight classes and their fontconfig equivalents, see
+/// `FcWeightFromOpenTypeDouble`.
+const FC_WEIGHT_MAP: [(u32, i32); 12] = [
+    (100, 0),
+    (200, 40),
+    (300, 50),
+    (350, 55),
+    (380, 75),
+    (400, 80),
+    (500, 100),
+    (600, 180),
+    (700, 200),
+    (800, 205),
+    (900, 210),
+    (1000, 215),
+];
+
+fn fc_weight_from_opentype(weight: u32) -> i32 {
+    let weight = weight.clamp(FC_WEIGHT_MAP[0].0, FC_WEIGHT_MAP[FC_WEIGHT_MAP.len() - 1].0);
+    let i = FC_WEIGHT_MAP
+        .iter()
+        .position(|&(ot, _)| weight <= ot)
+        .unwrap();
+    if i == 0 {
+        return FC_WEIGHT_MAP[0].1;
+    }
+    let (ot0, fc0) = FC_WEIGHT_MAP[i - 1];
+    let (ot1, fc1) = FC_WEIGHT_MAP[i];
+    fc0 + ((fc1 - fc0) as f32 * (weight - ot0) as f32 / (ot1 - ot0) as f32).round() as i32
+}
+
+fn parse_font_weight(arg: &str) -> anyhow::Result<u32> {
+    let weight = match arg
+        .to_ascii_lowercase()
+        .replace(&['-', ' '][..], "")
+        .as_str()
+    {
+        "thin" => 100,
+        "extralight" | "ultralight" => 200,
+        "light" => 300,
+        "semilight" | "demilight" => 350,
+        "book" => 380,
+        "regular" | "normal" => 400,
+        "medium" => 500,
+        "semibold" | "demibold" => 600,
+        "bold" => 700,
+        "extrabold" | "ultrabold" => 800,
+        "black" | "heavy" => 900,
+        "extrablack" | "ultrablack" => 1000,
+        _ => arg.parse()?,
+    };
+    anyhow::ensure!(
+        (1..=1000).contains(&weight),
+        "font weight should be between 1 and 1000"
+    );
+    Ok(weight)
+}
+
+fn parse_font_width(arg: &str) -> anyhow::Result<u32> {
+    let width = match arg
+        .to_ascii_lowercase()
+        .replace(&['-', ' '][..], "")
+        .as_str()
+    {
+        "ultracondensed" => 50,
+        "extracondensed" => 63,
+        "condensed" => 75,
+        "semicondensed" => 87,
+        "normal" => 100,
+        "semiexpanded" => 113,
+        "expanded" => 125,
+        "extraexpanded" => 150,
+        "ultraexpanded" => 200,
+        _ => arg.trim_end_matches('%').parse()?,
+    };
+    anyhow::ensure!(width > 0, "font width should be a positive percentage");
+    Ok(width)
+}
+
 pub fn parse_font_ppem(arg: &str) -> anyhow::Result<FontPpem> {
     let arg: Vec<_> = arg
         .split(|c| c == ' ' || c == ',')
@@ -375,20 +669,41 @@ pub fn parse_font_ppem(arg: &str) -> anyhow::Result<FontPpem> {
 }
 
 pub trait FontOpts {
-    fn font(&self) -> HbFont;
-    fn load_font(&mut self);
+    /// Font size when --font-size is not given, `FONT_SIZE_UPEM` for the
+    /// upem of the face.
+    const DEFAULT_FONT_SIZE: usize;
+    /// Bits of precision below a pixel in the font scale.
+    const SUBPIXEL_BITS: i32;
+
+    fn font(&self) -> Font;
+    fn fallback_font(&self, text: &str, after: Option<&Font>) -> Option<Font>;
+    fn load_font(&mut self) -> anyhow::Result<()>;
 }
 
-impl FontOpts for Options {
-    fn font(&self) -> HbFont {
-        unsafe { HbFont::from_raw(ffi::hb_font_reference(self.font_opts.font())) }
-    }
+/// Implement `FontOpts` for a tool's options with a `font_opts` field.
+macro_rules! impl_font_opts {
+    ($options:ty, $default_font_size:expr, $subpixel_bits:expr) => {
+        impl FontOpts for $options {
+            const DEFAULT_FONT_SIZE: usize = $default_font_size;
+            const SUBPIXEL_BITS: i32 = $subpixel_bits;
 
-    fn load_font(&mut self) {
-        self.font_opts.load_font();
-    }
+            fn font(&self) -> Font {
+                self.font_opts.font()
+            }
+            fn fallback_font(&self, text: &str, after: Option<&Font>) -> Option<Font> {
+                self.font_opts.fallback_font(text, after)
+            }
+
+            fn load_font(&mut self) -> anyhow::Result<()> {
+                self.font_opts
+                    .load_font(Self::DEFAULT_FONT_SIZE, Self::SUBPIXEL_BITS)
+            }
+        }
+    };
 }
 
+impl_font_opts!(Options, 256, 6);
+
 #[derive(Debug, Args)]
 pub struct TextOptions {
     /// Set input text
@@ -401,9 +716,20 @@ pub struct TextOptions {
     #[clap(long)]
     pub text_file: Option<PathBuf>,
 
-    /// Set input Unicode codepoints, hex numbers
-    #[clap(short = 'u', long)]
-    pub unicodes: Vec<u32>,
+    /// Set input text encoding
+    ///
+    /// With auto, a byte order mark selects UTF-8, UTF-16 or UTF-32, and
+    /// UTF-8 is assumed without one. Unless --utf8-clusters is given, cluster
+    /// values count code units of this encoding.
+    #[clap(arg_enum, long, default_value = "auto")]
+    pub text_encoding: TextEncoding,
+
+    /// Set input Unicode codepoints
+    ///
+    /// Hex numbers separated by commas or spaces, each optionally prefixed
+    /// with U+ or 0x, e.g. "U+0627,0x644 647".
+    #[clap(short = 'u', long, parse(try_from_str = parse_unicodes))]
+    pub unicodes: Option<String>,
 
     /// Set text context before each line
     #[clap(long)]
@@ -413,42 +739,156 @@ pub struct TextOptions {
     #[clap(long)]
     pub text_after: Option<String>,
 
+    /// Set Unicode codepoints context before each line, like --unicodes
+    #[clap(long, conflicts_with = "text-before", parse(try_from_str = parse_unicodes))]
+    pub unicodes_before: Option<String>,
+
+    /// Set Unicode codepoints context after each line, like --unicodes
+    #[clap(long, conflicts_with = "text-after", parse(try_from_str = parse_unicodes))]
+    pub unicodes_after: Option<String>,
+
+    /// Use the previous and next input lines as context for each line
+    ///
+    /// The context options still apply before the first line and after the
+    /// last one. A line is only shaped once the next one has been read.
+    #[clap(long)]
+    pub lines_as_context: bool,
+
     #[clap(skip = RefCell::new(None))]
-    lines: RefCell<Option<std::str::Lines<'static>>>,
+    source: RefCell<Option<TextSource>>,
+}
+
+/// Where lines of input text come from.
+enum TextSource {
+    /// --text or --unicodes, already split into lines.
+    Lines(std::vec::IntoIter<String>),
+    /// --text-file or standard input, read one line at a time.
+    Reader(Box<dyn BufRead>),
+}
+
+impl std::fmt::Debug for TextSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TextSource::Lines(lines) => f.debug_tuple("Lines").field(lines).finish(),
+            TextSource::Reader(_) => f.debug_tuple("Reader").finish(),
+        }
+    }
+}
+
+/// The text spelled by a list of hex code points, see --unicodes.
+fn parse_unicodes(arg: &str) -> anyhow::Result<String> {
+    arg.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|v| !v.is_empty())
+        .map(|v| {
+            let hex = ["U+", "u+", "0x", "0X"]
+                .iter()
+                .find_map(|prefix| v.strip_prefix(prefix))
+                .unwrap_or(v);
+            anyhow::ensure!(
+                !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit()),
+                "`{}' is not a hex code point",
+                v
+            );
+            let u = u32::from_str_radix(hex, 16)
+                .map_err(|_| anyhow::anyhow!("`{}' is out of the Unicode range", v))?;
+            char::from_u32(u)
+                .ok_or_else(|| anyhow::anyhow!("`{}' is not a Unicode scalar value", v))
+        })
+        .collect()
+}
+
+fn split_lines(text: &str) -> std::vec::IntoIter<String> {
+    text.lines()
+        .map(|line| line.to_string())
+        .collect::<Vec<_>>()
+        .into_iter()
 }
 
 impl TextOptions {
-    pub fn read(&mut self) {
-        static TEXT: OnceCell<String> = OnceCell::new();
-        let text = TEXT
-            .get_or_try_init(|| {
-                if let Some(ref path) = self.text_file {
-                    Ok(std::fs::read_to_string(path)
-                        .map_err(|err| anyhow::anyhow!("Can not open '{}'", path.display()))?)
-                } else if !self.unicodes.is_empty() {
-                    let s = self
-                        .unicodes
-                        .iter()
-                        .map(|u| char::try_from(*u).unwrap())
-                        .collect();
-                    Ok(s)
-                } else if let Some(ref text) = self.text {
-                    Ok(text.to_string())
-                } else {
-                    anyhow::bail!("None of text or unicodes or text-file provided.");
+    /// Set up reading lines from --text, --unicodes, --text-file or, if none
+    /// of those is given, standard input. "-" as --text-file is standard input
+    /// too.
+    pub fn read(&mut self) -> anyhow::Result<()> {
+        let source = if let Some(ref path) = self.text_file {
+            let reader: Box<dyn BufRead> = if path.as_os_str() == "-" {
+                Box::new(BufReader::new(std::io::stdin()))
+            } else {
+                let file = std::fs::File::open(path)
+                    .map_err(|err| anyhow::anyhow!("Can not open '{}': {}", path.display(), err))?;
+                Box::new(BufReader::new(file))
+            };
+            TextSource::Reader(reader)
+        } else if let Some(ref text) = self.unicodes {
+            TextSource::Lines(split_lines(text))
+        } else if let Some(ref text) = self.text {
+            TextSource::Lines(split_lines(text))
+        } else {
+            TextSource::Reader(Box::new(BufReader::new(std::io::stdin())))
+        };
+        self.source.replace(Some(source));
+        self.detect_encoding()
+    }
+
+    /// Replace an `auto` --text-encoding with the encoding of the input, and
+    /// skip a byte order mark of that encoding.
+    fn detect_encoding(&mut self) -> anyhow::Result<()> {
+        if let Some(TextSource::Reader(reader)) = self.source.get_mut() {
+            let bom = TextEncoding::from_bom(reader.fill_buf()?);
+            match bom {
+                Some((encoding, len))
+                    if self.text_encoding == TextEncoding::Auto
+                        || self.text_encoding == encoding =>
+                {
+                    self.text_encoding = encoding;
+                    reader.consume(len);
                 }
-            })
-            .unwrap();
-        self.lines.replace(Some(text.lines()));
+                _ => {}
+            }
+        }
+        if self.text_encoding == TextEncoding::Auto {
+            self.text_encoding = TextEncoding::Utf8;
+        }
+
+        let given = [
+            &self.text,
+            &self.unicodes,
+            &self.text_before,
+            &self.text_after,
+            &self.unicodes_before,
+            &self.unicodes_after,
+        ];
+        if let Some(text) = given
+            .into_iter()
+            .flatten()
+            .find(|text| !self.text_encoding.can_encode(text))
+        {
+            anyhow::bail!("`{}' can not be encoded in Latin-1", text);
+        }
+        Ok(())
     }
 
-    pub fn readline(&self) -> Option<&'static str> {
-        assert!(self.lines.borrow().is_some());
-        self.lines
-            .borrow_mut()
-            .as_mut()
-            .map(|lines| lines.next())
-            .unwrap()
+    /// --text-before or --unicodes-before.
+    pub fn text_before(&self) -> Option<&str> {
+        self.text_before
+            .as_deref()
+            .or(self.unicodes_before.as_deref())
+    }
+
+    /// --text-after or --unicodes-after.
+    pub fn text_after(&self) -> Option<&str> {
+        self.text_after
+            .as_deref()
+            .or(self.unicodes_after.as_deref())
+    }
+
+    /// The next line of input without its line terminator, or `None` at the
+    /// end of the input.
+    pub fn readline(&self) -> anyhow::Result<Option<String>> {
+        let mut source = self.source.borrow_mut();
+        match source.as_mut().expect("read() sets up the input") {
+            TextSource::Lines(lines) => Ok(lines.next()),
+            TextSource::Reader(reader) => self.text_encoding.read_line(reader),
+        }
     }
 }
 
@@ -456,35 +896,56 @@ pub trait TextOpts {
     fn text_before(&self) -> Option<&str>;
     fn text_after(&self) -> Option<&str>;
 
-    fn read(&mut self);
-    fn readline(&self) -> Option<&'static str>;
+    fn lines_as_context(&self) -> bool;
+    /// The encoding of the input, known once `read` has been called.
+    fn text_encoding(&self) -> TextEncoding;
+
+    fn read(&mut self) -> anyhow::Result<()>;
+    fn readline(&self) -> anyhow::Result<Option<String>>;
 }
 
-impl TextOpts for Options {
-    fn text_before(&self) -> Option<&str> {
-        self.text.text_before.as_ref().map(|v| v.as_str())
-    }
-    fn text_after(&self) -> Option<&str> {
-        self.text.text_after.as_ref().map(|v| v.as_str())
-    }
+/// Implement `TextOpts` for a tool's options with a `text` field.
+macro_rules! impl_text_opts {
+    ($options:ty) => {
+        impl TextOpts for $options {
+            fn text_before(&self) -> Option<&str> {
+                self.text.text_before()
+            }
+            fn text_after(&self) -> Option<&str> {
+                self.text.text_after()
+            }
+            fn lines_as_context(&self) -> bool {
+                self.text.lines_as_context
+            }
+            fn text_encoding(&self) -> TextEncoding {
+                self.text.text_encoding
+            }
 
-    fn read(&mut self) {
-        self.text.read();
-    }
-    fn readline(&self) -> Option<&'static str> {
-        self.text.readline()
-    }
+            fn read(&mut self) -> anyhow::Result<()> {
+                self.text.read()
+            }
+            fn readline(&self) -> anyhow::Result<Option<String>> {
+                self.text.readline()
+            }
+        }
+    };
 }
 
+impl_text_opts!(Options);
+
 #[derive(Debug, Args)]
 pub struct ShapeOptions {
     /// List available shapers and quit
-    #[clap(long)]
+    #[clap(long, exclusive = true)]
     pub list_shapers: bool,
 
-    /// Set shapers to try
-    #[clap(long, parse(try_from_str = parse_shapers))]
-    pub shapers: Vec<std::ffi::CString>,
+    /// Set comma-separated list of shapers to try
+    #[clap(long, use_value_delimiter = true)]
+    pub shapers: Vec<String>,
+
+    /// Report the shaper used for each line on stderr
+    #[clap(long)]
+    pub verbose: bool,
 
     /// Set text direction, one of ltr/rtl/ttb/btt (default: auto)
     #[clap(long)]
@@ -537,109 +998,107 @@ pub struct ShapeOptions {
     #[clap(short = 'n', long, default_value = "1")]
     /// Run shaper N times (default: 1)
     pub num_iterations: usize,
-}
 
-fn parse_shapers(arg: &str) -> anyhow::Result<std::ffi::CString> {
-    unsafe {
-        let hb_shapers = ffi::hb_shape_list_shapers();
-        while !(*hb_shapers).is_null() {
-            let cstr = CStr::from_ptr(*hb_shapers);
-            if arg == cstr.to_str().unwrap() {
-                return Ok(cstr.to_owned());
-            }
-        }
-    }
-    anyhow::bail!("Unknown or unsupported shaper: {}", arg)
+    #[clap(long)]
+    /// Time each shaping run and report statistics on stderr
+    pub benchmark: bool,
+
+    #[clap(long, requires = "benchmark")]
+    /// Only shape, without rendering or writing any output
+    pub no_output: bool,
+
+    #[clap(skip)]
+    shaper: once_cell::unsync::OnceCell<Shaper>,
 }
 
 pub trait ShapeOpts {
+    fn list_shapers(&self) -> bool;
     fn utf8_clusters(&self) -> bool;
     fn verify(&self) -> bool;
     fn num_iterations(&self) -> usize;
+    fn benchmark(&self) -> bool;
+    fn no_output(&self) -> bool;
+    fn verbose(&self) -> bool;
 
-    unsafe fn populate_buffer(
+    fn populate_buffer(
         &self,
-        buffer: *mut ffi::hb_buffer_t,
+        buffer: &mut Buffer,
         text: &str,
         text_before: Option<&str>,
         text_after: Option<&str>,
     );
-    unsafe fn shape(
-        &self,
-        font: *mut ffi::hb_font_t,
-        buffer: *mut ffi::hb_buffer_t,
-    ) -> anyhow::Result<bool>;
+    /// Shape `buffer`, populated with `text`, through the library's
+    /// `ShapeRequest`, then normalize and verify it as asked. Returns how
+    /// long harfbuzz took to shape, leaving out --verify.
+    fn shape(&self, font: &Font, text: &str, buffer: &mut Buffer) -> anyhow::Result<Duration>;
+
+    /// The --features and --shapers, parsed the first time they are needed.
+    /// Call it before writing any output, so that mistakes in them are
+    /// reported first.
+    fn shaper(&self) -> Result<&Shaper, Error>;
 }
 
-impl ShapeOpts for Options {
-    fn utf8_clusters(&self) -> bool {
-        self.shape.utf8_clusters
-    }
-    fn verify(&self) -> bool {
-        self.shape.verify
-    }
-    fn num_iterations(&self) -> usize {
-        self.shape.num_iterations
-    }
+/// Implement `ShapeOpts` for a tool's options with `text`, `shape` and
+/// `features` fields.
+macro_rules! impl_shape_opts {
+    ($options:ty) => {
+        impl ShapeOpts for $options {
+            fn list_shapers(&self) -> bool {
+                self.shape.list_shapers
+            }
+            fn utf8_clusters(&self) -> bool {
+                self.shape.utf8_clusters
+            }
+            fn verify(&self) -> bool {
+                self.shape.verify
+            }
+            fn num_iterations(&self) -> usize {
+                self.shape.num_iterations
+            }
+            fn benchmark(&self) -> bool {
+                self.shape.benchmark
+            }
+            fn no_output(&self) -> bool {
+                self.shape.no_output
+            }
+            fn verbose(&self) -> bool {
+                self.shape.verbose
+            }
 
-    unsafe fn populate_buffer(
-        &self,
-        buffer: *mut ffi::hb_buffer_t,
-        text: &str,
-        text_before: Option<&str>,
-        text_after: Option<&str>,
-    ) {
-        self.shape
-            .populate_buffer(buffer, text, text_before, text_after)
-    }
+            fn populate_buffer(
+                &self,
+                buffer: &mut Buffer,
+                text: &str,
+                text_before: Option<&str>,
+                text_after: Option<&str>,
+            ) {
+                self.shape.populate_buffer(
+                    buffer,
+                    self.text.text_encoding,
+                    text,
+                    text_before,
+                    text_after,
+                )
+            }
 
-    unsafe fn shape(
-        &self,
-        font: *mut ffi::hb_font_t,
-        buffer: *mut ffi::hb_buffer_t,
-    ) -> anyhow::Result<bool> {
-        // let mut text_buffer: *mut ffi::hb_buffer_t = std::ptr::null_mut();
-        // if self.shape.verify {
-        //     text_buffer = ffi::hb_buffer_create();
-        //     ffi::hb_buffer_append(text_buffer, buffer, 0, u32::MAX);
-        // }
-
-        let features: Vec<_> = self
-            .features
-            .features
-            .iter()
-            .map(|feat| {
-                let mut feature = MaybeUninit::zeroed();
-                ffi::hb_feature_from_string(
-                    feat.as_ptr() as _,
-                    feat.len() as _,
-                    feature.as_mut_ptr(),
-                );
-                feature.assume_init()
-            })
-            .collect();
-
-        let mut shapers: Vec<*const std::os::raw::c_char> =
-            self.shape.shapers.iter().map(|s| s.as_ptr()).collect();
-        shapers.push(std::ptr::null());
-        let ret = ffi::hb_shape_full(
-            font,
-            buffer,
-            features.as_ptr(),
-            features.len() as _,
-            shapers.as_ptr(),
-        );
-        if ret == 0 {
-            anyhow::bail!("Shaping failed");
-        }
+            fn shape(
+                &self,
+                font: &Font,
+                text: &str,
+                buffer: &mut Buffer,
+            ) -> anyhow::Result<Duration> {
+                self.shape.shape(&self.features, font, text, buffer)
+            }
 
-        if self.shape.normalize_glyphs {
-            ffi::hb_buffer_normalize_glyphs(buffer);
+            fn shaper(&self) -> Result<&Shaper, Error> {
+                self.shape.shaper(&self.features)
+            }
         }
-        Ok(true)
-    }
+    };
 }
 
+impl_shape_opts!(Options);
+
 #[derive(Debug, Args)]
 pub struct FeatureOptions {
     /// Font features
@@ -650,8 +1109,9 @@ pub struct FeatureOptions {
     /// and the global values are also accepted, though not documented below.
     /// CSS string escapes are not supported.
     /// The range indices refer to the positions between Unicode characters,
-    /// unless the --utf8-clusters is provided, in which case range indices
-    /// refer to UTF-8 byte indices. The position before the first character
+    /// or UTF-16 code units with a UTF-16 --text-encoding, unless the
+    /// --utf8-clusters is provided, in which case range indices refer to
+    /// UTF-8 byte indices. The position before the first character
     /// is always 0.
     ///
     /// The format is Python-esque.  Here is how it all works:  
@@ -706,6 +1166,19 @@ impl FromStr for OutputFormat {
     }
 }
 
+#[derive(Copy, Clone, Debug, ArgEnum)]
+pub enum AnsiBlocks {
+    Half,
+    Quarter,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ArgEnum)]
+pub enum ImageProtocol {
+    None = 0,
+    Iterm2 = 1,
+    Kitty = 2,
+}
+
 #[derive(Args)]
 pub struct OutputAndFormatOptions {
     /// Set output file-name (default: stdout)
@@ -718,8 +1191,95 @@ pub struct OutputAndFormatOptions {
     /// Supported output formats are: ansi/png/svg/pdf/ps/eps
     pub output_format: Option<OutputFormat>,
 
+    /// Set block characters used by ansi output, half/quarter (default: half)
+    #[clap(arg_enum, long, default_value_t = AnsiBlocks::Half)]
+    pub ansi_blocks: AnsiBlocks,
+
+    /// Set inline image protocol for png output to a terminal,
+    /// none/iterm2/kitty (default: auto)
+    #[clap(arg_enum, long)]
+    pub image_protocol: Option<ImageProtocol>,
+
     #[clap(skip)]
-    pub output_fp: Option<Box<dyn std::io::Write>>,
+    pub output_fp: RefCell<Option<Box<dyn std::io::Write>>>,
+}
+
+fn stdout_is_terminal() -> bool {
+    unsafe { libc::isatty(libc::STDOUT_FILENO) == 1 }
+}
+
+impl OutputAndFormatOptions {
+    /// The requested output format, falling back to the extension of
+    /// `--output-file`, then to ansi on a terminal and png otherwise.
+    pub fn format(&self) -> OutputFormat {
+        if let Some(format) = self.output_format {
+            return format;
+        }
+        if let Some(ref path) = self.output_file {
+            return std::path::Path::new(path)
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .and_then(|ext| OutputFormat::from_str(&ext.to_ascii_lowercase()).ok())
+                .unwrap_or(OutputFormat::PNG);
+        }
+        if !stdout_is_terminal() || self.protocol() != ImageProtocol::None {
+            OutputFormat::PNG
+        } else {
+            OutputFormat::ANSI
+        }
+    }
+
+    fn writes_to_stdout(&self) -> bool {
+        self.output_file.as_ref().map_or(true, |path| path == "-")
+    }
+
+    /// The protocol used to show png output inline, from `--image-protocol`
+    /// or detected from the environment when writing to a terminal.
+    pub fn protocol(&self) -> ImageProtocol {
+        if !self.writes_to_stdout() {
+            return ImageProtocol::None;
+        }
+        if let Some(protocol) = self.image_protocol {
+            return protocol;
+        }
+        if !stdout_is_terminal() {
+            return ImageProtocol::None;
+        }
+        let env_is = |name: &str, value: &str| {
+            std::env::var(name).map_or(false, |v| v.eq_ignore_ascii_case(value))
+        };
+        if env_is("TERM_PROGRAM", "iTerm.app") || env_is("LC_TERMINAL", "iTerm2") {
+            ImageProtocol::Iterm2
+        } else if env_is("TERM", "xterm-kitty") || std::env::var_os("KITTY_WINDOW_ID").is_some() {
+            ImageProtocol::Kitty
+        } else {
+            ImageProtocol::None
+        }
+    }
+
+    /// Take the output stream, opening `--output-file` or stdout if none was set.
+    pub fn get_file_handle(&self) -> anyhow::Result<Box<dyn std::io::Write>> {
+        take_file_handle(&self.output_fp, self.output_file.as_deref())
+    }
+}
+
+fn take_file_handle(
+    output_fp: &RefCell<Option<Box<dyn std::io::Write>>>,
+    output_file: Option<&str>,
+) -> anyhow::Result<Box<dyn std::io::Write>> {
+    if let Some(fp) = output_fp.borrow_mut().take() {
+        return Ok(fp);
+    }
+    let fp: Box<dyn std::io::Write> = match output_file {
+        Some(path) if path != "-" => {
+            let file = std::fs::File::create(path)
+                .map_err(Error::Output)
+                .with_context(|| format!("Cannot open output file '{}'", path))?;
+            Box::new(std::io::BufWriter::new(file))
+        }
+        _ => Box::new(std::io::stdout()),
+    };
+    Ok(fp)
 }
 
 impl std::fmt::Debug for OutputAndFormatOptions {
@@ -731,6 +1291,332 @@ impl std::fmt::Debug for OutputAndFormatOptions {
     }
 }
 
+#[derive(Copy, Clone, Debug, ArgEnum)]
+pub enum SerializeFormat {
+    Text,
+    Json,
+}
+
+impl SerializeFormat {
+    fn to_hb(&self) -> ffi::hb_buffer_serialize_format_t {
+        match self {
+            SerializeFormat::Text => ffi::HB_BUFFER_SERIALIZE_FORMAT_TEXT,
+            SerializeFormat::Json => ffi::HB_BUFFER_SERIALIZE_FORMAT_JSON,
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct ShapeFormatOptions {
+    /// Set output file-name (default: stdout)
+    #[clap(long)]
+    pub output_file: Option<String>,
+
+    /// Set output serialization format, text/json (default: text)
+    #[clap(arg_enum, long, default_value_t = SerializeFormat::Text)]
+    pub output_format: SerializeFormat,
+
+    /// Output glyph indices instead of names
+    #[clap(long)]
+    pub no_glyph_names: bool,
+
+    /// Do not output glyph positions
+    #[clap(long)]
+    pub no_positions: bool,
+
+    /// Do not output glyph advances
+    #[clap(long)]
+    pub no_advances: bool,
+
+    /// Do not output cluster indices
+    #[clap(long)]
+    pub no_clusters: bool,
+
+    /// Output glyph extents
+    #[clap(long)]
+    pub show_extents: bool,
+
+    /// Output glyph flags
+    #[clap(long)]
+    pub show_flags: bool,
+
+    /// No Extra Data; Do not output clusters or advances
+    #[clap(long)]
+    pub ned: bool,
+
+    /// Prefix each line of output with its corresponding input text
+    #[clap(long)]
+    pub show_text: bool,
+
+    /// Prefix each line of output with its corresponding input codepoint(s)
+    #[clap(long)]
+    pub show_unicode: bool,
+
+    /// Prefix each line of output with its corresponding input line number
+    #[clap(long)]
+    pub show_line_num: bool,
+
+    #[clap(skip)]
+    pub output_fp: RefCell<Option<Box<dyn std::io::Write>>>,
+}
+
+impl ShapeFormatOptions {
+    pub fn serialize_format(&self) -> ffi::hb_buffer_serialize_format_t {
+        self.output_format.to_hb()
+    }
+
+    pub fn serialize_flags(&self) -> ffi::hb_buffer_serialize_flags_t {
+        let mut flags = ffi::HB_BUFFER_SERIALIZE_FLAG_DEFAULT;
+        if self.no_glyph_names {
+            flags |= ffi::HB_BUFFER_SERIALIZE_FLAG_NO_GLYPH_NAMES;
+        }
+        if self.no_positions {
+            flags |= ffi::HB_BUFFER_SERIALIZE_FLAG_NO_POSITIONS;
+        }
+        if self.no_advances || self.ned {
+            flags |= ffi::HB_BUFFER_SERIALIZE_FLAG_NO_ADVANCES;
+        }
+        if self.no_clusters || self.ned {
+            flags |= ffi::HB_BUFFER_SERIALIZE_FLAG_NO_CLUSTERS;
+        }
+        if self.show_extents {
+            flags |= ffi::HB_BUFFER_SERIALIZE_FLAG_GLYPH_EXTENTS;
+        }
+        if self.show_flags {
+            flags |= ffi::HB_BUFFER_SERIALIZE_FLAG_GLYPH_FLAGS;
+        }
+        flags
+    }
+
+    /// Take the output stream, opening `--output-file` or stdout if none was set.
+    pub fn get_file_handle(&self) -> anyhow::Result<Box<dyn std::io::Write>> {
+        take_file_handle(&self.output_fp, self.output_file.as_deref())
+    }
+}
+
+impl std::fmt::Debug for ShapeFormatOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ShapeFormatOptions")
+            .field("output_file", &self.output_file)
+            .field("output_format", &self.output_format)
+            .field("serialize_flags", &self.serialize_flags())
+            .field("show_text", &self.show_text)
+            .field("show_unicode", &self.show_unicode)
+            .field("show_line_num", &self.show_line_num)
+            .finish()
+    }
+}
+
+/// Options of the hb-shape tool.
+#[derive(Debug, Parser)]
+#[clap(name = "hb-shape", author, version=version(), about, long_about = None)]
+pub struct HbShapeOptions {
+    #[clap(flatten, next_help_heading = "Font options")]
+    pub font_opts: FontOptions,
+
+    #[clap(flatten, next_help_heading = "Shape options")]
+    pub text: TextOptions,
+
+    #[clap(flatten, next_help_heading = "Shape options")]
+    pub shape: ShapeOptions,
+
+    #[clap(flatten, next_help_heading = "Features options")]
+    pub features: FeatureOptions,
+
+    #[clap(flatten, next_help_heading = "Output destination & format options")]
+    pub output: ShapeFormatOptions,
+}
+
+// Shape at the font's upem without subpixel scaling, so positions are in font
+// units.
+impl_font_opts!(HbShapeOptions, FONT_SIZE_UPEM, 0);
+
+impl_text_opts!(HbShapeOptions);
+
+impl_shape_opts!(HbShapeOptions);
+
+/// One entry of a glyph, codepoint or name-ID selection.
+#[derive(Clone, Copy, Debug)]
+pub enum SetRange {
+    All,
+    Range(u32, u32),
+}
+
+fn parse_set_range(arg: &str, radix: u32) -> anyhow::Result<SetRange> {
+    let arg = arg.trim();
+    if arg == "*" {
+        return Ok(SetRange::All);
+    }
+    let parse = |v: &str| {
+        let v = v.trim();
+        let v = if radix == 16 {
+            v.trim_start_matches("U+")
+                .trim_start_matches("u+")
+                .trim_start_matches("0x")
+        } else {
+            v
+        };
+        u32::from_str_radix(v, radix).map_err(|err| anyhow::anyhow!("'{}': {}", v, err))
+    };
+    match arg.split_once('-') {
+        Some((first, last)) => {
+            let (first, last) = (parse(first)?, parse(last)?);
+            anyhow::ensure!(first <= last, "invalid range '{}'", arg);
+            Ok(SetRange::Range(first, last))
+        }
+        None => {
+            let value = parse(arg)?;
+            Ok(SetRange::Range(value, value))
+        }
+    }
+}
+
+fn parse_decimal_range(arg: &str) -> anyhow::Result<SetRange> {
+    parse_set_range(arg, 10)
+}
+
+fn parse_hex_range(arg: &str) -> anyhow::Result<SetRange> {
+    parse_set_range(arg, 16)
+}
+
+#[derive(Args)]
+pub struct SubsetOptions {
+    /// Set output file-name (default: stdout)
+    #[clap(long)]
+    pub output_file: Option<String>,
+
+    /// Specify glyph IDs or ranges to include in the subset, eg. 1,5-10 or *
+    #[clap(long, use_value_delimiter = true, parse(try_from_str = parse_decimal_range))]
+    pub gids: Vec<SetRange>,
+
+    /// Specify Unicode codepoint ranges to include in the subset, eg. 0041-005A,U+0627 or *
+    #[clap(long, use_value_delimiter = true, parse(try_from_str = parse_hex_range))]
+    pub unicode_ranges: Vec<SetRange>,
+
+    /// Drop the specified tables
+    #[clap(long, use_value_delimiter = true, conflicts_with = "keep-tables")]
+    pub drop_tables: Vec<String>,
+
+    /// Keep only the specified tables, dropping every other one
+    #[clap(long, use_value_delimiter = true)]
+    pub keep_tables: Vec<String>,
+
+    /// Specify set of OpenType layout feature tags that will be preserved, or *
+    #[clap(long, use_value_delimiter = true)]
+    pub layout_features: Vec<String>,
+
+    /// Subset specified record IDs in the 'name' table, eg. 0-6 or *
+    #[clap(long = "name-IDs", use_value_delimiter = true, parse(try_from_str = parse_decimal_range))]
+    pub name_ids: Vec<SetRange>,
+
+    /// Remove hinting instructions
+    #[clap(long)]
+    pub no_hinting: bool,
+
+    /// Keep glyph IDs of the original font, leaving holes for removed glyphs
+    #[clap(long)]
+    pub retain_gids: bool,
+
+    /// Remove CFF/CFF2 subroutines
+    #[clap(long)]
+    pub desubroutinize: bool,
+
+    #[clap(skip)]
+    pub output_fp: RefCell<Option<Box<dyn std::io::Write>>>,
+}
+
+impl SubsetOptions {
+    /// Take the output stream, opening `--output-file` or stdout if none was set.
+    pub fn get_file_handle(&self) -> anyhow::Result<Box<dyn std::io::Write>> {
+        take_file_handle(&self.output_fp, self.output_file.as_deref())
+    }
+}
+
+impl std::fmt::Debug for SubsetOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SubsetOptions")
+            .field("output_file", &self.output_file)
+            .field("gids", &self.gids)
+            .field("unicode_ranges", &self.unicode_ranges)
+            .field("drop_tables", &self.drop_tables)
+            .field("keep_tables", &self.keep_tables)
+            .field("layout_features", &self.layout_features)
+            .field("name_ids", &self.name_ids)
+            .field("no_hinting", &self.no_hinting)
+            .field("retain_gids", &self.retain_gids)
+            .field("desubroutinize", &self.desubroutinize)
+            .finish()
+    }
+}
+
+/// Options of the hb-subset tool.
+#[derive(Debug, Parser)]
+#[clap(name = "hb-subset", author, version=version(), about, long_about = None)]
+pub struct HbSubsetOptions {
+    #[clap(flatten, next_help_heading = "Font options")]
+    pub font_opts: FontOptions,
+
+    #[clap(flatten, next_help_heading = "Text options")]
+    pub text: TextOptions,
+
+    #[clap(flatten, next_help_heading = "Subset options")]
+    pub subset: SubsetOptions,
+}
+
+impl HbSubsetOptions {
+    /// Whether any of --text, --text-file or -u was given.
+    pub fn has_text(&self) -> bool {
+        self.text.text.is_some() || self.text.text_file.is_some() || self.text.unicodes.is_some()
+    }
+}
+
+impl_font_opts!(HbSubsetOptions, FONT_SIZE_UPEM, 0);
+
+impl_text_opts!(HbSubsetOptions);
+
+#[derive(Args)]
+pub struct InfoOptions {
+    /// Set output file-name (default: stdout)
+    #[clap(long)]
+    pub output_file: Option<String>,
+
+    /// Set output format, text/json (default: text)
+    #[clap(arg_enum, long, default_value_t = SerializeFormat::Text)]
+    pub output_format: SerializeFormat,
+
+    #[clap(skip)]
+    pub output_fp: RefCell<Option<Box<dyn std::io::Write>>>,
+}
+
+impl InfoOptions {
+    /// Take the output stream, opening `--output-file` or stdout if none was set.
+    pub fn get_file_handle(&self) -> anyhow::Result<Box<dyn std::io::Write>> {
+        take_file_handle(&self.output_fp, self.output_file.as_deref())
+    }
+}
+
+impl std::fmt::Debug for InfoOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InfoOptions")
+            .field("output_file", &self.output_file)
+            .field("output_format", &self.output_format)
+            .finish()
+    }
+}
+
+/// Options of the hb-info tool.
+#[derive(Debug, Parser)]
+#[clap(name = "hb-info", author, version=version(), about, long_about = None)]
+pub struct HbInfoOptions {
+    #[clap(flatten, next_help_heading = "Font options")]
+    pub font_opts: FontOptions,
+
+    #[clap(flatten, next_help_heading = "Output destination & format options")]
+    pub info: InfoOptions,
+}
+
+impl_font_opts!(HbInfoOptions, FONT_SIZE_UPEM, 0);
+
 #[derive(Debug, Copy, Clone)]
 pub struct FontExtents {
     pub ascent: f64,
@@ -763,6 +1649,23 @@ impl std::fmt::Display for ViewMargin {
     }
 }
 
+/// Line width for --wrap-width.
+#[derive(Clone, Copy, Debug)]
+pub enum WrapWidth {
+    Pixels(f64),
+    Ems(f64),
+}
+
+impl WrapWidth {
+    /// The width in pixels, for a font of `font_size` pixels per em.
+    pub fn to_pixels(self, font_size: f64) -> f64 {
+        match self {
+            WrapWidth::Pixels(width) => width,
+            WrapWidth::Ems(width) => width * font_size.abs(),
+        }
+    }
+}
+
 #[derive(Debug, Args)]
 pub struct ViewOptions {
     /// Annotate output rendering
@@ -788,6 +1691,14 @@ pub struct ViewOptions {
     /// Margin around output (default: 16)
     #[clap(long, parse(try_from_str = parse_margin))]
     pub margin: Option<ViewMargin>,
+
+    /// Wrap lines longer than this width, in pixels, or in ems with an "em"
+    /// suffix
+    ///
+    /// Lines are broken at the break opportunities of the Unicode line
+    /// breaking algorithm.
+    #[clap(long, parse(try_from_str = parse_wrap_width))]
+    pub wrap_width: Option<WrapWidth>,
 }
 
 fn parse_font_extents(arg: &str) -> anyhow::Result<FontExtents> {
@@ -853,23 +1764,20 @@ fn parse_margin(arg: &str) -> anyhow::Result<ViewMargin> {
     Ok(m)
 }
 
+fn parse_wrap_width(arg: &str) -> anyhow::Result<WrapWidth> {
+    let arg = arg.trim();
+    let width = if let Some(ems) = arg.strip_suffix("em") {
+        WrapWidth::Ems(ems.trim().parse()?)
+    } else {
+        WrapWidth::Pixels(arg.strip_suffix("px").unwrap_or(arg).trim().parse()?)
+    };
+    let (WrapWidth::Pixels(value) | WrapWidth::Ems(value)) = width;
+    anyhow::ensure!(value > 0., "wrap width should be a positive number");
+    Ok(width)
+}
+
 impl ShapeOptions {
-    pub unsafe fn setup_buffer(&self, buffer: *mut ffi::hb_buffer_t) {
-        if let Some(ref direction) = self.direction {
-            let direction = direction.to_hb();
-            ffi::hb_buffer_set_direction(buffer, direction);
-        }
-        if let Some(ref script) = self.script {
-            let script = ffi::hb_script_from_string(script.as_ptr() as _, script.len() as _);
-            ffi::hb_buffer_set_script(buffer, script)
-        }
-        let sys_language = std::env::var("LANG").ok();
-        let language = self.language.as_ref().or_else(|| sys_language.as_ref());
-        if let Some(language) = language {
-            let language =
-                ffi::hb_language_from_string(language.as_ptr() as _, language.len() as _);
-            ffi::hb_buffer_set_language(buffer, language);
-        }
+    pub fn setup_buffer(&self, buffer: &mut Buffer) {
         let flags = ffi::HB_BUFFER_FLAG_DEFAULT
             | if self.bot { ffi::HB_BUFFER_FLAG_BOT } else { 0 }
             | if self.eot { ffi::HB_BUFFER_FLAG_EOT } else { 0 }
@@ -885,53 +1793,318 @@ impl ShapeOptions {
             }
             | 0;
 
-        ffi::hb_buffer_set_flags(buffer, flags);
+        buffer.set_flags(flags);
         if let Some(invisible_glyph) = self.invisible_glyph {
-            ffi::hb_buffer_set_invisible_glyph(buffer, invisible_glyph);
+            buffer.set_invisible_glyph(invisible_glyph);
+        }
+    }
+
+    /// The library request for shaping `text` with `font` as these options
+    /// ask. The segment properties of every buffer come from it.
+    pub fn to_shape_request(
+        &self,
+        features: &FeatureOptions,
+        font: &Font,
+        text: &str,
+    ) -> ShapeRequest {
+        ShapeRequest {
+            text: text.to_string(),
+            font: font.clone(),
+            features: features.features.clone(),
+            direction: self.direction.clone().map(Into::into),
+            script: self.script.clone(),
+            language: self.language.clone().or_else(|| std::env::var("LANG").ok()),
+            cluster_level: self.cluster_level.clone().into(),
         }
-        ffi::hb_buffer_set_cluster_level(buffer, self.cluster_level.to_hb());
-        ffi::hb_buffer_guess_segment_properties(buffer);
     }
 
-    pub unsafe fn copy_buffer_properties(dst: *mut ffi::hb_buffer_t, src: *mut ffi::hb_buffer_t) {
-        let mut props: MaybeUninit<ffi::hb_segment_properties_t> = MaybeUninit::uninit();
-        ffi::hb_buffer_get_segment_properties(src, props.as_mut_ptr());
-        ffi::hb_buffer_set_segment_properties(dst, props.as_ptr());
-        ffi::hb_buffer_set_flags(dst, ffi::hb_buffer_get_flags(src));
-        ffi::hb_buffer_set_cluster_level(dst, ffi::hb_buffer_get_cluster_level(src));
+    /// The --features and --shapers lists, parsed for shaping once.
+    pub fn shaper(&self, features: &FeatureOptions) -> Result<&Shaper, Error> {
+        self.shaper
+            .get_or_try_init(|| Shaper::new(&features.features, &self.shapers))
     }
 
-    pub unsafe fn populate_buffer(
+    pub fn copy_buffer_properties(dst: &mut Buffer, src: &Buffer) {
+        dst.set_segment_properties(&src.segment_properties());
+        dst.set_flags(src.flags());
+        dst.set_cluster_level(src.cluster_level());
+    }
+
+    pub fn populate_buffer(
         &self,
-        buffer: *mut ffi::hb_buffer_t,
+        buffer: &mut Buffer,
+        encoding: TextEncoding,
         text: &str,
         text_before: Option<&str>,
         text_after: Option<&str>,
     ) {
-        ffi::hb_buffer_clear_contents(buffer);
+        // Clusters come out in code units of the encoding the text is added
+        // in.
+        let encoding = if self.utf8_clusters {
+            TextEncoding::Utf8
+        } else {
+            encoding
+        };
+
+        // harfbuzz takes the pre-context from text before the item when
+        // adding to an empty buffer, and the post-context from text after the
+        // item on every add. So the context goes in as adds without items,
+        // first and last, and clusters count from the start of `text`.
+        buffer.clear_contents();
         if let Some(text_before) = text_before {
             let len = text_before.len();
-            ffi::hb_buffer_add_utf8(buffer, text_before.as_ptr() as _, len as _, len as _, 0);
+            encoding.add_to_buffer(buffer, text_before, len..len);
         }
-        let text_len = text.len();
-        ffi::hb_buffer_add_utf8(buffer, text.as_ptr() as _, text_len as _, 0, text_len as _);
+        encoding.add_to_buffer(buffer, text, 0..text.len());
         if let Some(text_after) = text_after {
-            let len = text_after.len();
-            ffi::hb_buffer_add_utf8(buffer, text_after.as_ptr() as _, len as _, len as _, 0);
+            encoding.add_to_buffer(buffer, text_after, 0..0);
         }
 
-        if !self.utf8_clusters {
+        if !self.utf8_clusters && encoding == TextEncoding::Utf8 {
             // Reset cluster values to refer to Unicode character index
             // instead of UTF-8 index.
-            let num_glyphs = ffi::hb_buffer_get_length(buffer);
-            let mut length = 0u32;
-            let mut info = ffi::hb_buffer_get_glyph_infos(buffer, &mut length as *mut _);
-            for i in 0..num_glyphs {
-                (*info).cluster = i;
-                info = info.offset(1);
+            for (i, info) in buffer.glyph_infos_mut().iter_mut().enumerate() {
+                info.cluster = i as u32;
             }
         }
 
         self.setup_buffer(buffer);
     }
+
+    pub fn shape(
+        &self,
+        features: &FeatureOptions,
+        font: &Font,
+        text: &str,
+        buffer: &mut Buffer,
+    ) -> anyhow::Result<Duration> {
+        let request = self.to_shape_request(features, font, text);
+        let shaper = self.shaper(features)?;
+        let text_buffer = if self.verify {
+            let mut text_buffer = Buffer::new();
+            text_buffer.append(buffer, 0, buffer.len());
+            Some(text_buffer)
+        } else {
+            None
+        };
+
+        let elapsed = shape::shape_buffer(&request, shaper, buffer)?;
+
+        if self.normalize_glyphs {
+            buffer.normalize_glyphs();
+        }
+
+        if let Some(text_buffer) = text_buffer {
+            self.verify_buffer(buffer, &text_buffer, font, shaper)?;
+        }
+        Ok(elapsed)
+    }
+
+    fn has_monotone_clusters(&self) -> bool {
+        shape::ClusterLevel::from(self.cluster_level.clone()).is_monotone()
+    }
+
+    /// Sanity check the shaping result in `buffer` against the original
+    /// text in `text_buffer`.
+    pub fn verify_buffer(
+        &self,
+        buffer: &mut Buffer,
+        text_buffer: &Buffer,
+        font: &Font,
+        shaper: &Shaper,
+    ) -> anyhow::Result<()> {
+        self.verify_buffer_monotone(buffer)?;
+        self.verify_buffer_safe_to_break(buffer, text_buffer, font, shaper)?;
+        Ok(())
+    }
+
+    fn verify_buffer_monotone(&self, buffer: &Buffer) -> anyhow::Result<()> {
+        // Check that clusters are monotone.
+        if !self.has_monotone_clusters() {
+            return Ok(());
+        }
+        let is_forward = crate::hb_direction_is_forward(buffer.direction());
+        let info = buffer.glyph_infos();
+        for i in 1..info.len() {
+            if info[i - 1].cluster != info[i].cluster
+                && (info[i - 1].cluster < info[i].cluster) != is_forward
+            {
+                anyhow::bail!("clusters are not monotone at glyphs {} and {}", i - 1, i);
+            }
+        }
+        Ok(())
+    }
+
+    fn verify_buffer_safe_to_break(
+        &self,
+        buffer: &mut Buffer,
+        text_buffer: &Buffer,
+        font: &Font,
+        shaper: &Shaper,
+    ) -> anyhow::Result<()> {
+        // Cannot perform this check without monotone clusters.
+        // Then again, unsafe-to-break flag is much harder to use without
+        // monotone clusters.
+        if !self.has_monotone_clusters() {
+            return Ok(());
+        }
+
+        // Check that breaking up shaping at safe-to-break is indeed safe.
+        let flags = buffer.flags();
+        let mut fragment = buffer.create_similar();
+        let mut reconstruction = buffer.create_similar();
+
+        let info = buffer.glyph_infos();
+        let text = text_buffer.glyph_infos();
+        let num_glyphs = info.len();
+        let num_chars = text.len();
+
+        // Chop text and shape fragments.
+        let forward = crate::hb_direction_is_forward(buffer.direction());
+        let mut text_start = if forward { 0 } else { num_chars };
+        let mut text_end = text_start;
+        let mut start = 0;
+        for end in 1..=num_glyphs {
+            if end < num_glyphs
+                && (info[end].cluster == info[end - 1].cluster
+                    || info[if forward { end } else { end - 1 }].mask
+                        & ffi::HB_GLYPH_FLAG_UNSAFE_TO_BREAK
+                        != 0)
+            {
+                continue;
+            }
+
+            // Shape the segment of text corresponding to glyphs start..end.
+            if end == num_glyphs {
+                if forward {
+                    text_end = num_chars;
+                } else {
+                    text_start = 0;
+                }
+            } else if forward {
+                let cluster = info[end].cluster;
+                while text_end < num_chars && text[text_end].cluster < cluster {
+                    text_end += 1;
+                }
+            } else {
+                let cluster = info[end - 1].cluster;
+                while text_start > 0 && text[text_start - 1].cluster >= cluster {
+                    text_start -= 1;
+                }
+            }
+            if text_start >= text_end {
+                anyhow::bail!(
+                    "safe-to-break test found no characters for glyphs {}..{}",
+                    start,
+                    end
+                );
+            }
+
+            fragment.clear_contents();
+            let mut fragment_flags = flags;
+            if 0 < text_start {
+                fragment_flags &= !ffi::HB_BUFFER_FLAG_BOT;
+            }
+            if text_end < num_chars {
+                fragment_flags &= !ffi::HB_BUFFER_FLAG_EOT;
+            }
+            fragment.set_flags(fragment_flags);
+
+            fragment.append(text_buffer, text_start, text_end);
+            if !shaper.shape(font, &mut fragment) {
+                anyhow::bail!(
+                    "shaping failed while shaping fragment of characters {}..{}",
+                    text_start,
+                    text_end
+                );
+            }
+            reconstruction.append(&fragment, 0, fragment.len());
+
+            if forward {
+                text_start = text_end;
+            } else {
+                text_end = text_start;
+            }
+            start = end;
+        }
+
+        if reconstruction.diff(buffer, 0) != 0 {
+            let glyph = first_glyph_difference(&reconstruction, buffer);
+
+            // Return the reconstructed result instead so it can be inspected.
+            buffer.set_length(0);
+            buffer.append(&reconstruction, 0, reconstruction.len());
+
+            anyhow::bail!("Safe-to-break test failed at glyph {}", glyph);
+        }
+
+        Ok(())
+    }
+}
+
+/// Index of the first glyph whose id, cluster or position differ.
+fn first_glyph_difference(a: &Buffer, b: &Buffer) -> usize {
+    let (a_info, b_info) = (a.glyph_infos(), b.glyph_infos());
+    let (a_pos, b_pos) = (a.glyph_positions(), b.glyph_positions());
+    let len = a_info.len().min(b_info.len());
+    (0..len)
+        .find(|&i| {
+            a_info[i].codepoint != b_info[i].codepoint
+                || a_info[i].cluster != b_info[i].cluster
+                || a_pos[i].x_advance != b_pos[i].x_advance
+                || a_pos[i].y_advance != b_pos[i].y_advance
+                || a_pos[i].x_offset != b_pos[i].x_offset
+                || a_pos[i].y_offset != b_pos[i].y_offset
+        })
+        .unwrap_or(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fc_weight_from_opentype() {
+        assert_eq!(super::fc_weight_from_opentype(400), 80);
+        assert_eq!(super::fc_weight_from_opentype(700), 200);
+        assert_eq!(super::fc_weight_from_opentype(450), 90);
+        assert_eq!(super::fc_weight_from_opentype(1), 0);
+        assert_eq!(super::fc_weight_from_opentype(1000), 215);
+    }
+
+    #[test]
+    fn family_match() {
+        assert!(same_family("Noto Sans CJK JP", "noto sans cjk jp"));
+        assert!(same_family("DejaVuSans", "DejaVu Sans"));
+        assert!(!same_family("DejaVu Sans", "DejaVu Serif"));
+        assert!(is_generic_family("Sans-Serif"));
+        assert!(is_generic_family(" monospace "));
+        assert!(!is_generic_family("Helvetica"));
+    }
+
+    #[test]
+    fn parse_unicodes() {
+        assert_eq!(
+            super::parse_unicodes("U+0041,u+0042 0x43 0X44").unwrap(),
+            "ABCD"
+        );
+        assert_eq!(
+            super::parse_unicodes("e9, 1F600").unwrap(),
+            "\u{e9}\u{1F600}"
+        );
+        assert_eq!(super::parse_unicodes("").unwrap(), "");
+        for arg in ["U+", "zz", "-41", "D800", "110000", "FFFFFFFFF"] {
+            assert!(super::parse_unicodes(arg).is_err(), "{:?}", arg);
+        }
+    }
+
+    #[test]
+    fn parse_wrap_width() {
+        assert!(matches!(super::parse_wrap_width("300"), Ok(WrapWidth::Pixels(w)) if w == 300.));
+        assert!(matches!(super::parse_wrap_width("12.5px"), Ok(WrapWidth::Pixels(w)) if w == 12.5));
+        assert!(matches!(super::parse_wrap_width("20em"), Ok(WrapWidth::Ems(w)) if w == 20.));
+        for arg in ["0", "-1em", "em", "wide", "10pt"] {
+            assert!(super::parse_wrap_width(arg).is_err(), "{:?}", arg);
+        }
+    }
 }
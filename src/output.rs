@@ -1,20 +1,25 @@
-use harfbuzz_sys as ffi;
+use crate::font_fallback::FontRun;
+use crate::hb::Buffer;
 
 pub trait Output {
     type Opts: clap::Parser;
-    fn create(buffer: *mut ffi::hb_buffer_t, opts: &Self::Opts) -> Self;
-    fn new_line(&self);
-    unsafe fn consume_text(
+    fn create(buffer: &Buffer, opts: &Self::Opts) -> anyhow::Result<Self>
+    where
+        Self: Sized;
+    fn new_line(&mut self);
+    fn consume_text(
         &mut self,
-        buffer: *mut ffi::hb_buffer_t,
+        buffer: &Buffer,
         text: &str,
         utf8_clusters: bool,
-    );
-    unsafe fn consume_glyphs(
+    ) -> anyhow::Result<()>;
+    fn consume_glyphs(
         &mut self,
-        buffer: *mut ffi::hb_buffer_t,
+        buffer: &Buffer,
         text: &str,
         utf8_clusters: bool,
-    );
-    unsafe fn finish(&mut self, buffer: *mut ffi::hb_buffer_t, opts: &Self::Opts);
+        font_runs: &[FontRun],
+        opts: &Self::Opts,
+    ) -> anyhow::Result<()>;
+    fn finish(&mut self, buffer: &Buffer, opts: &Self::Opts) -> anyhow::Result<()>;
 }
@@ -0,0 +1,193 @@
+use std::io::Write;
+
+use harfbuzz_sys as ffi;
+
+use crate::error::Error;
+use crate::font_fallback::FontRun;
+use crate::hb::{Buffer, Font};
+use crate::options::HbShapeOptions;
+use crate::output::Output;
+
+/// Serializes each shaped line as text or JSON, like `hb-shape`.
+pub struct OutputBuffer {
+    fp: Box<dyn Write>,
+    format: ffi::hb_buffer_serialize_format_t,
+    flags: ffi::hb_buffer_serialize_flags_t,
+    show_text: bool,
+    show_unicode: bool,
+    show_line_num: bool,
+    line_no: usize,
+    gs: String,
+}
+
+/// Call `serialize` until it has consumed items `start..end` of the buffer,
+/// appending its output to `gs`.
+fn serialize_range(
+    start: u32,
+    end: u32,
+    gs: &mut String,
+    serialize: impl Fn(u32, u32, *mut std::os::raw::c_char, u32, &mut u32) -> u32,
+) {
+    let mut buf = [0u8; 1024];
+    let mut start = start;
+    loop {
+        let mut consumed = 0;
+        start += serialize(
+            start,
+            end,
+            buf.as_mut_ptr() as _,
+            buf.len() as _,
+            &mut consumed,
+        );
+        if consumed == 0 {
+            break;
+        }
+        gs.push_str(&String::from_utf8_lossy(&buf[..consumed as usize]));
+        if start >= end {
+            break;
+        }
+    }
+}
+
+impl OutputBuffer {
+    fn serialize_line_no(&mut self) {
+        if self.show_line_num {
+            self.gs.push_str(&format!("{}: ", self.line_no));
+        }
+    }
+
+    fn flush_line(&mut self) -> Result<(), Error> {
+        self.gs.push('\n');
+        self.fp.write_all(self.gs.as_bytes())?;
+        self.gs.clear();
+        Ok(())
+    }
+}
+
+impl Output for OutputBuffer {
+    type Opts = HbShapeOptions;
+
+    fn create(_buffer: &Buffer, opts: &HbShapeOptions) -> anyhow::Result<OutputBuffer> {
+        Ok(OutputBuffer {
+            fp: opts.output.get_file_handle()?,
+            format: opts.output.serialize_format(),
+            flags: opts.output.serialize_flags(),
+            show_text: opts.output.show_text,
+            show_unicode: opts.output.show_unicode,
+            show_line_num: opts.output.show_line_num,
+            line_no: 0,
+            gs: String::new(),
+        })
+    }
+
+    fn new_line(&mut self) {
+        self.line_no += 1;
+    }
+
+    fn consume_text(
+        &mut self,
+        buffer: &Buffer,
+        text: &str,
+        _utf8_clusters: bool,
+    ) -> anyhow::Result<()> {
+        if self.show_text {
+            self.serialize_line_no();
+            self.gs.push_str(text);
+            self.flush_line()?;
+        }
+
+        if self.show_unicode {
+            self.serialize_line_no();
+            let format = self.format;
+            let num_items = buffer.len() as u32;
+            serialize_range(
+                0,
+                num_items,
+                &mut self.gs,
+                |start, end, buf, size, consumed| unsafe {
+                    ffi::hb_buffer_serialize_unicode(
+                        buffer.as_ptr(),
+                        start,
+                        end,
+                        buf,
+                        size,
+                        consumed,
+                        format,
+                        ffi::HB_BUFFER_SERIALIZE_FLAG_DEFAULT,
+                    )
+                },
+            );
+            self.flush_line()?;
+        }
+        Ok(())
+    }
+
+    fn consume_glyphs(
+        &mut self,
+        buffer: &Buffer,
+        _text: &str,
+        _utf8_clusters: bool,
+        font_runs: &[FontRun],
+        _opts: &HbShapeOptions,
+    ) -> anyhow::Result<()> {
+        self.serialize_line_no();
+        let (format, flags) = (self.format, self.flags);
+        let serialize = |gs: &mut String, font: &Font, start: usize, end: usize| {
+            serialize_range(
+                start as _,
+                end as _,
+                gs,
+                |start, end, buf, size, consumed| unsafe {
+                    ffi::hb_buffer_serialize_glyphs(
+                        buffer.as_ptr(),
+                        start,
+                        end,
+                        buf,
+                        size,
+                        consumed,
+                        font.as_ptr(),
+                        format,
+                        flags,
+                    )
+                },
+            )
+        };
+
+        match font_runs {
+            [] => {}
+            [run] => serialize(&mut self.gs, &run.font, 0, buffer.len()),
+            runs => {
+                // Glyph names come from the font each run was shaped with, so
+                // every run is serialized on its own. harfbuzz puts each call
+                // in a list of its own, opened with `[` or, after the first
+                // glyph of the buffer, with a separator; the list of the line
+                // is put together here instead.
+                let separator = if format == ffi::HB_BUFFER_SERIALIZE_FORMAT_JSON {
+                    ','
+                } else {
+                    '|'
+                };
+                let mut items = String::new();
+                self.gs.push('[');
+                for (i, run) in runs.iter().filter(|run| run.start < run.end).enumerate() {
+                    items.clear();
+                    serialize(&mut items, &run.font, run.start, run.end);
+                    debug_assert!(items.ends_with(']'));
+                    if i > 0 {
+                        self.gs.push(separator);
+                    }
+                    if items.len() >= 2 {
+                        self.gs.push_str(&items[1..items.len() - 1]);
+                    }
+                }
+                self.gs.push(']');
+            }
+        }
+        Ok(self.flush_line()?)
+    }
+
+    fn finish(&mut self, _buffer: &Buffer, _opts: &HbShapeOptions) -> anyhow::Result<()> {
+        self.fp.flush().map_err(Error::Output)?;
+        Ok(())
+    }
+}
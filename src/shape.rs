@@ -0,0 +1,308 @@
+//! Shaping without the command-line option structs.
+
+use std::ffi::{CStr, CString};
+use std::mem::MaybeUninit;
+use std::os::raw::c_char;
+use std::time::{Duration, Instant};
+
+use harfbuzz_sys as ffi;
+
+use crate::error::Error;
+use crate::hb::{Buffer, Font};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Ltr,
+    Rtl,
+    Ttb,
+    Btt,
+}
+
+impl Direction {
+    pub(crate) fn to_hb(self) -> ffi::hb_direction_t {
+        match self {
+            Direction::Ltr => ffi::HB_DIRECTION_LTR,
+            Direction::Rtl => ffi::HB_DIRECTION_RTL,
+            Direction::Ttb => ffi::HB_DIRECTION_TTB,
+            Direction::Btt => ffi::HB_DIRECTION_BTT,
+        }
+    }
+
+    /// `None` for `HB_DIRECTION_INVALID`.
+    pub(crate) fn from_hb(direction: ffi::hb_direction_t) -> Option<Direction> {
+        match direction {
+            ffi::HB_DIRECTION_LTR => Some(Direction::Ltr),
+            ffi::HB_DIRECTION_RTL => Some(Direction::Rtl),
+            ffi::HB_DIRECTION_TTB => Some(Direction::Ttb),
+            ffi::HB_DIRECTION_BTT => Some(Direction::Btt),
+            _ => None,
+        }
+    }
+}
+
+/// How harfbuzz merges clusters, see `hb_buffer_cluster_level_t`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ClusterLevel {
+    MonotoneGraphemes,
+    MonotoneCharacters,
+    Characters,
+}
+
+impl ClusterLevel {
+    pub(crate) fn to_hb(self) -> ffi::hb_buffer_cluster_level_t {
+        match self {
+            ClusterLevel::MonotoneGraphemes => ffi::HB_BUFFER_CLUSTER_LEVEL_MONOTONE_GRAPHEMES,
+            ClusterLevel::MonotoneCharacters => ffi::HB_BUFFER_CLUSTER_LEVEL_MONOTONE_CHARACTERS,
+            ClusterLevel::Characters => ffi::HB_BUFFER_CLUSTER_LEVEL_CHARACTERS,
+        }
+    }
+
+    /// Whether cluster values are monotone in the glyph order.
+    pub fn is_monotone(self) -> bool {
+        self != ClusterLevel::Characters
+    }
+}
+
+/// Everything needed to shape one run of text. The command-line tools
+/// build theirs with `ShapeOptions::to_shape_request`.
+#[derive(Clone, Debug)]
+pub struct ShapeRequest {
+    pub text: String,
+    pub font: Font,
+    /// Features in the syntax of `hb_feature_from_string`, e.g. `"kern"`,
+    /// `"-liga"` or `"aalt[3:5]=2"`.
+    pub features: Vec<String>,
+    /// Guessed from the text if `None`.
+    pub direction: Option<Direction>,
+    /// ISO 15924 tag, guessed from the text if `None`.
+    pub script: Option<String>,
+    /// BCP 47 tag, harfbuzz' default language if `None`.
+    pub language: Option<String>,
+    pub cluster_level: ClusterLevel,
+}
+
+impl ShapeRequest {
+    pub fn new(text: impl Into<String>, font: Font) -> ShapeRequest {
+        ShapeRequest {
+            text: text.into(),
+            font,
+            features: Vec::new(),
+            direction: None,
+            script: None,
+            language: None,
+            cluster_level: ClusterLevel::MonotoneGraphemes,
+        }
+    }
+
+    /// Set the direction, script, language and cluster level of `buffer`,
+    /// and guess the properties left out from its text.
+    pub(crate) fn setup_buffer(&self, buffer: &mut Buffer) {
+        if let Some(direction) = self.direction {
+            buffer.set_direction(direction.to_hb());
+        }
+        if let Some(ref script) = self.script {
+            let script =
+                unsafe { ffi::hb_script_from_string(script.as_ptr() as _, script.len() as _) };
+            buffer.set_script(script);
+        }
+        if let Some(ref language) = self.language {
+            let language = unsafe {
+                ffi::hb_language_from_string(language.as_ptr() as _, language.len() as _)
+            };
+            buffer.set_language(language);
+        }
+        buffer.set_cluster_level(self.cluster_level.to_hb());
+        buffer.guess_segment_properties();
+    }
+}
+
+/// One shaped glyph. Positions are in font units scaled by the font's scale.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ShapedGlyph {
+    pub glyph_id: u32,
+    /// Byte offset into the request's text of the cluster this glyph is in.
+    pub cluster: u32,
+    pub x_advance: i32,
+    pub y_advance: i32,
+    pub x_offset: i32,
+    pub y_offset: i32,
+}
+
+/// The result of shaping a `ShapeRequest`, in visual order.
+#[derive(Clone, Debug)]
+pub struct GlyphRun {
+    /// Resolved direction, useful when the request left it to be guessed.
+    pub direction: Direction,
+    pub glyphs: Vec<ShapedGlyph>,
+}
+
+impl GlyphRun {
+    pub fn from_buffer(buffer: &Buffer) -> GlyphRun {
+        let glyphs = buffer
+            .glyph_infos()
+            .iter()
+            .zip(buffer.glyph_positions())
+            .map(|(info, pos)| ShapedGlyph {
+                glyph_id: info.codepoint,
+                cluster: info.cluster,
+                x_advance: pos.x_advance,
+                y_advance: pos.y_advance,
+                x_offset: pos.x_offset,
+                y_offset: pos.y_offset,
+            })
+            .collect();
+        GlyphRun {
+            // Shaping resolves the direction; harfbuzz shapes left to right
+            // without one.
+            direction: Direction::from_hb(buffer.direction()).unwrap_or(Direction::Ltr),
+            glyphs,
+        }
+    }
+
+    /// Sum of the advances of all glyphs.
+    pub fn advance(&self) -> (i32, i32) {
+        self.glyphs.iter().fold((0, 0), |(x, y), glyph| {
+            (x + glyph.x_advance, y + glyph.y_advance)
+        })
+    }
+}
+
+/// Shape `request` with the default shaper list.
+pub fn shape(request: &ShapeRequest) -> anyhow::Result<GlyphRun> {
+    let shaper = Shaper::new(&request.features, &[])?;
+    let mut buffer = Buffer::new();
+    buffer.add_utf8(&request.text, 0, request.text.len());
+    shape_buffer(request, &shaper, &mut buffer)?;
+    Ok(GlyphRun::from_buffer(&buffer))
+}
+
+/// Shape `buffer` as `shape` shapes `request`, with `shaper` parsed from its
+/// features. `buffer` holds the text of `request`, possibly with context or
+/// in another encoding. Returns how long `hb_shape_full` took.
+pub(crate) fn shape_buffer(
+    request: &ShapeRequest,
+    shaper: &Shaper,
+    buffer: &mut Buffer,
+) -> anyhow::Result<Duration> {
+    request.setup_buffer(buffer);
+
+    let start = Instant::now();
+    let shaped = shaper.shape(&request.font, buffer);
+    let elapsed = start.elapsed();
+    anyhow::ensure!(shaped, "Shaping failed");
+    Ok(elapsed)
+}
+
+/// Names of the shapers compiled into harfbuzz, in the order they are tried.
+pub fn supported_shapers() -> Vec<&'static str> {
+    let mut shapers = Vec::new();
+    unsafe {
+        let mut hb_shapers = ffi::hb_shape_list_shapers();
+        while !(*hb_shapers).is_null() {
+            shapers.push(CStr::from_ptr(*hb_shapers).to_str().unwrap());
+            hb_shapers = hb_shapers.offset(1);
+        }
+    }
+    shapers
+}
+
+/// Features and shapers parsed once, for shaping many buffers.
+pub struct Shaper {
+    features: Vec<ffi::hb_feature_t>,
+    /// Owners of the strings `shapers` points to.
+    shaper_names: Vec<CString>,
+    /// Null-terminated, or only the null for the default list.
+    shapers: Vec<*const c_char>,
+}
+
+impl std::fmt::Debug for Shaper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Shaper")
+            .field("num_features", &self.features.len())
+            .field("shapers", &self.shaper_names)
+            .finish()
+    }
+}
+
+impl Shaper {
+    /// Parse `features` in the syntax of `hb_feature_from_string`, and check
+    /// `shapers` against the shapers harfbuzz was built with. An empty list
+    /// of shapers tries them all.
+    pub fn new(features: &[String], shapers: &[String]) -> Result<Shaper, Error> {
+        let features = parse_features(features)?;
+        let supported = supported_shapers();
+        let shaper_names = shapers
+            .iter()
+            .map(|shaper| {
+                if supported.contains(&shaper.as_str()) {
+                    CString::new(shaper.as_str()).map_err(|_| Error::UnknownShaper(shaper.clone()))
+                } else {
+                    Err(Error::UnknownShaper(shaper.clone()))
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut shapers: Vec<*const c_char> = shaper_names.iter().map(|s| s.as_ptr()).collect();
+        shapers.push(std::ptr::null());
+        Ok(Shaper {
+            features,
+            shaper_names,
+            shapers,
+        })
+    }
+
+    /// `hb_shape_full` on `buffer`; false if none of the shapers could shape
+    /// it.
+    pub fn shape(&self, font: &Font, buffer: &mut Buffer) -> bool {
+        unsafe {
+            ffi::hb_shape_full(
+                font.as_ptr(),
+                buffer.as_ptr(),
+                self.features.as_ptr(),
+                self.features.len() as _,
+                self.shapers.as_ptr(),
+            ) != 0
+        }
+    }
+
+    /// Name of the shaper of the shape plan `shape` would use for `buffer`.
+    pub fn shaper_name(&self, font: &Font, buffer: &Buffer) -> String {
+        let props = buffer.segment_properties();
+        unsafe {
+            let mut num_coords = 0;
+            let coords = ffi::hb_font_get_var_coords_normalized(font.as_ptr(), &mut num_coords);
+            let plan = ffi::hb_shape_plan_create_cached2(
+                font.face().as_ptr(),
+                &props,
+                self.features.as_ptr(),
+                self.features.len() as _,
+                coords,
+                num_coords,
+                self.shapers.as_ptr(),
+            );
+            let shaper = CStr::from_ptr(ffi::hb_shape_plan_get_shaper(plan))
+                .to_string_lossy()
+                .into_owned();
+            ffi::hb_shape_plan_destroy(plan);
+            shaper
+        }
+    }
+}
+
+/// Parse features in the syntax of `hb_feature_from_string`.
+pub fn parse_features(features: &[String]) -> Result<Vec<ffi::hb_feature_t>, Error> {
+    features
+        .iter()
+        .map(|feat| unsafe {
+            let mut feature = MaybeUninit::zeroed();
+            let ok = ffi::hb_feature_from_string(
+                feat.as_ptr() as _,
+                feat.len() as _,
+                feature.as_mut_ptr(),
+            );
+            if ok == 0 {
+                return Err(Error::InvalidFeature(feat.clone()));
+            }
+            Ok(feature.assume_init())
+        })
+        .collect()
+}
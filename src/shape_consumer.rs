@@ -1,20 +1,27 @@
 use harfbuzz_sys as ffi;
 
+use crate::benchmark::Timings;
 use crate::consumer::Consumer;
+use crate::error::Error;
+use crate::font_fallback::{shape_fallback, FontRun};
+use crate::hb::Buffer;
 use crate::options::{FontOpts, ShapeOpts, TextOpts};
 use crate::output::Output;
+use crate::shape::supported_shapers;
 
 pub struct ShapeConsumer<Out> {
-    buffer: *mut ffi::hb_buffer_t,
-    out: Out,
-}
-
-impl<T> Drop for ShapeConsumer<T> {
-    fn drop(&mut self) {
-        unsafe {
-            ffi::hb_buffer_destroy(self.buffer);
-        }
-    }
+    buffer: Buffer,
+    line_no: usize,
+    /// `None` with --no-output.
+    out: Option<Out>,
+    /// Timings of all lines, with --benchmark.
+    timings: Timings,
+    /// Lines that failed to shape or to pass --verify.
+    failed_lines: usize,
+    /// With --lines-as-context, the line shaped last and the line read ahead,
+    /// `Some(None)` at the end of the input.
+    prev_line: Option<String>,
+    next_line: Option<Option<String>>,
 }
 
 impl<Out: Output> Consumer for ShapeConsumer<Out>
@@ -23,45 +30,127 @@ where
 {
     type Opts = Out::Opts;
 
-    fn with_options(opts: &<Out as Output>::Opts) -> ShapeConsumer<Out> {
-        let buffer = unsafe { ffi::hb_buffer_create() };
-        let out = Out::create(buffer, opts);
-        Self { buffer, out }
+    fn handle_early_options(opts: &Out::Opts) {
+        if opts.list_shapers() {
+            for shaper in supported_shapers() {
+                println!("{}", shaper);
+            }
+            std::process::exit(0);
+        }
     }
 
-    unsafe fn consume_line(&mut self, opts: &Out::Opts) -> anyhow::Result<bool> {
-        let text = opts
-            .readline()
-            .ok_or_else(|| anyhow::anyhow!("no more line"))?;
+    fn with_options(opts: &<Out as Output>::Opts) -> anyhow::Result<ShapeConsumer<Out>> {
+        opts.shaper()?;
+        let buffer = Buffer::new();
+        let out = if opts.no_output() {
+            None
+        } else {
+            Some(Out::create(&buffer, opts)?)
+        };
+        Ok(Self {
+            buffer,
+            line_no: 0,
+            out,
+            timings: Timings::default(),
+            failed_lines: 0,
+            prev_line: None,
+            next_line: None,
+        })
+    }
+
+    fn consume_line(&mut self, opts: &Out::Opts) -> anyhow::Result<bool> {
+        let text = match self.next_line.take() {
+            Some(line) => line,
+            None => opts.readline()?,
+        };
+        let text = match text {
+            Some(text) => text,
+            None => return Ok(false),
+        };
+        self.line_no += 1;
 
-        self.out.new_line();
+        let prev_line = if opts.lines_as_context() {
+            self.next_line = Some(opts.readline()?);
+            self.prev_line.replace(text.clone())
+        } else {
+            None
+        };
+        let text_before = prev_line.as_deref().or_else(|| opts.text_before());
+        let text_after = match self.next_line {
+            Some(Some(ref line)) => Some(line.as_str()),
+            _ => opts.text_after(),
+        };
 
+        if let Some(out) = self.out.as_mut() {
+            out.new_line();
+        }
+
+        let font = opts.font();
+        let mut timings = Timings::default();
         for n in 0..opts.num_iterations() {
-            opts.populate_buffer(self.buffer, &text, opts.text_before(), opts.text_after());
+            opts.populate_buffer(&mut self.buffer, &text, text_before, text_after);
 
-            if n == 1 {
-                self.out
-                    .consume_text(self.buffer, &text, opts.utf8_clusters());
+            if n == 0 {
+                if let Some(out) = self.out.as_mut() {
+                    out.consume_text(&self.buffer, &text, opts.utf8_clusters())?;
+                }
             }
 
-            if let Err(err) = opts.shape(opts.font().as_ptr(), self.buffer) {
-                eprintln!("{}", err);
-                if ffi::hb_buffer_get_content_type(self.buffer)
-                    == ffi::HB_BUFFER_CONTENT_TYPE_GLYPHS
-                {
-                    break;
+            match opts.shape(&font, &text, &mut self.buffer) {
+                Ok(elapsed) => timings.push(elapsed, self.buffer.len()),
+                Err(err) => {
+                    eprintln!("error: line {}: {:?}: {}", self.line_no, text, err);
+                    self.failed_lines += 1;
+                    if self.buffer.content_type() == ffi::HB_BUFFER_CONTENT_TYPE_GLYPHS {
+                        break;
+                    }
+                    return Ok(true);
                 }
-                return Ok(true);
             }
         }
-        self.out
-            .consume_glyphs(self.buffer, &text, opts.utf8_clusters());
+
+        if opts.verbose() {
+            let shaper = opts.shaper()?.shaper_name(&font, &self.buffer);
+            eprintln!("line {}: shaper: {}", self.line_no, shaper);
+        }
+
+        if opts.benchmark() {
+            eprintln!("line {}: {}", self.line_no, timings.summary());
+            self.timings.extend(&timings);
+        }
+
+        let out = match self.out.as_mut() {
+            Some(out) => out,
+            None => return Ok(true),
+        };
+        let font_runs = if self.buffer.content_type() == ffi::HB_BUFFER_CONTENT_TYPE_GLYPHS {
+            match shape_fallback(opts, &mut self.buffer, &text, text_before, text_after) {
+                Ok(font_runs) => font_runs,
+                Err(err) => {
+                    eprintln!("error: line {}: {:?}: {}", self.line_no, text, err);
+                    self.failed_lines += 1;
+                    vec![FontRun::new(font, 0, self.buffer.len())]
+                }
+            }
+        } else {
+            Vec::new()
+        };
+        out.consume_glyphs(&self.buffer, &text, opts.utf8_clusters(), &font_runs, opts)?;
         Ok(true)
     }
 
-    unsafe fn finish(&mut self, opts: &Out::Opts) {
-        self.out.finish(self.buffer, opts);
-        ffi::hb_buffer_destroy(self.buffer);
-        self.buffer = std::ptr::null_mut();
+    fn finish(&mut self, opts: &Out::Opts) -> anyhow::Result<()> {
+        if opts.benchmark() {
+            eprintln!("total: {}", self.timings.summary());
+        }
+        if let Some(out) = self.out.as_mut() {
+            out.finish(&self.buffer, opts)?;
+        }
+        // Lines that failed were reported as they happened; still write the
+        // rest of the output, but don't exit successfully.
+        if self.failed_lines > 0 {
+            return Err(Error::ShapingFailed(self.failed_lines).into());
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,123 @@
+use std::collections::BTreeSet;
+use std::io::Write;
+
+use harfbuzz_sys as ffi;
+
+use crate::error::Error;
+use crate::hb::Face;
+use crate::options::{FontOpts, HbSubsetOptions, SetRange, TextOpts};
+
+unsafe fn hb_tag(tag: &str) -> ffi::hb_tag_t {
+    ffi::hb_tag_from_string(tag.as_ptr() as _, tag.len() as _)
+}
+
+/// Add `ranges` to `set`, where `SetRange::All` means every value up to `max`,
+/// or the whole value space if `max` is `None`.
+unsafe fn add_ranges(set: *mut ffi::hb_set_t, ranges: &[SetRange], max: Option<u32>) {
+    for range in ranges {
+        match *range {
+            SetRange::All => match max {
+                Some(max) => ffi::hb_set_add_range(set, 0, max),
+                None => {
+                    ffi::hb_set_clear(set);
+                    ffi::hb_set_invert(set);
+                    return;
+                }
+            },
+            SetRange::Range(first, last) => ffi::hb_set_add_range(set, first, last),
+        }
+    }
+}
+
+unsafe fn setup_input(
+    opts: &HbSubsetOptions,
+    face: &Face,
+    text_unicodes: &BTreeSet<char>,
+    input: *mut ffi::hb_subset_input_t,
+) {
+    let subset = &opts.subset;
+
+    let unicodes = ffi::hb_subset_input_unicode_set(input);
+    for &c in text_unicodes {
+        ffi::hb_set_add(unicodes, c as u32);
+    }
+    add_ranges(unicodes, &subset.unicode_ranges, None);
+
+    let num_glyphs = face.glyph_count();
+    let glyphs = ffi::hb_subset_input_glyph_set(input);
+    add_ranges(glyphs, &subset.gids, num_glyphs.checked_sub(1));
+
+    let drop_tables = ffi::hb_subset_input_set(input, ffi::HB_SUBSET_SETS_DROP_TABLE_TAG);
+    for tag in subset.drop_tables.iter() {
+        ffi::hb_set_add(drop_tables, hb_tag(tag));
+    }
+    if !subset.keep_tables.is_empty() {
+        let keep: Vec<_> = subset.keep_tables.iter().map(|tag| hb_tag(tag)).collect();
+        for tag in face.table_tags() {
+            if !keep.contains(&tag) {
+                ffi::hb_set_add(drop_tables, tag);
+            }
+        }
+    }
+
+    if !subset.layout_features.is_empty() {
+        let features = ffi::hb_subset_input_set(input, ffi::HB_SUBSET_SETS_LAYOUT_FEATURE_TAG);
+        ffi::hb_set_clear(features);
+        if subset.layout_features.iter().any(|tag| tag == "*") {
+            ffi::hb_set_invert(features);
+        } else {
+            for tag in subset.layout_features.iter() {
+                ffi::hb_set_add(features, hb_tag(tag));
+            }
+        }
+    }
+
+    if !subset.name_ids.is_empty() {
+        let name_ids = ffi::hb_subset_input_set(input, ffi::HB_SUBSET_SETS_NAME_ID);
+        ffi::hb_set_clear(name_ids);
+        add_ranges(name_ids, &subset.name_ids, None);
+    }
+
+    let mut flags = ffi::hb_subset_input_get_flags(input);
+    if subset.no_hinting {
+        flags |= ffi::HB_SUBSET_FLAGS_NO_HINTING;
+    }
+    if subset.retain_gids {
+        flags |= ffi::HB_SUBSET_FLAGS_RETAIN_GIDS;
+    }
+    if subset.desubroutinize {
+        flags |= ffi::HB_SUBSET_FLAGS_DESUBROUTINIZE;
+    }
+    ffi::hb_subset_input_set_flags(input, flags);
+}
+
+/// Subset the font of `opts` and write the result to its output file.
+pub fn subset(opts: &HbSubsetOptions) -> anyhow::Result<()> {
+    let face = opts.font().face();
+
+    let mut text_unicodes = BTreeSet::new();
+    if opts.has_text() {
+        while let Some(line) = opts.readline()? {
+            text_unicodes.extend(line.chars());
+        }
+    }
+
+    let result = unsafe {
+        let input = ffi::hb_subset_input_create_or_fail();
+        anyhow::ensure!(!input.is_null(), "Failed to create subset input");
+        setup_input(opts, &face, &text_unicodes, input);
+
+        let result = ffi::hb_subset_or_fail(face.as_ptr(), input);
+        ffi::hb_subset_input_destroy(input);
+        anyhow::ensure!(!result.is_null(), "Subsetting failed");
+        Face::from_raw(result)
+    };
+
+    let blob = result.blob();
+    anyhow::ensure!(!blob.is_empty(), "Subsetting produced an empty font");
+    let mut fp = opts.subset.get_file_handle()?;
+    fp.write_all(blob.as_bytes())
+        .and_then(|_| fp.flush())
+        .map_err(Error::Output)?;
+    Ok(())
+}
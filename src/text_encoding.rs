@@ -0,0 +1,341 @@
+//! Input text in encodings other than UTF-8, for --text-encoding.
+//!
+//! Lines are decoded to `String` when read. They are encoded again when
+//! they are added to a buffer, so that harfbuzz reports clusters in the
+//! code units of the input encoding.
+
+use std::io::{BufRead, Read};
+use std::ops::Range;
+
+use clap::ArgEnum;
+
+use crate::hb::Buffer;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ArgEnum)]
+pub enum TextEncoding {
+    /// Detect UTF-8, UTF-16 or UTF-32 from a byte order mark, UTF-8 without
+    /// one.
+    Auto,
+    Utf8,
+    Utf16le,
+    Utf16be,
+    Utf32le,
+    Utf32be,
+    Latin1,
+}
+
+impl TextEncoding {
+    /// The encoding whose byte order mark `bytes` starts with, and the
+    /// length of the mark.
+    pub fn from_bom(bytes: &[u8]) -> Option<(TextEncoding, usize)> {
+        // The UTF-32LE mark starts with the UTF-16LE one, so test it first.
+        const BOMS: [(&[u8], TextEncoding); 5] = [
+            (&[0xEF, 0xBB, 0xBF], TextEncoding::Utf8),
+            (&[0xFF, 0xFE, 0x00, 0x00], TextEncoding::Utf32le),
+            (&[0x00, 0x00, 0xFE, 0xFF], TextEncoding::Utf32be),
+            (&[0xFF, 0xFE], TextEncoding::Utf16le),
+            (&[0xFE, 0xFF], TextEncoding::Utf16be),
+        ];
+        BOMS.iter()
+            .find(|(bom, _)| bytes.starts_with(bom))
+            .map(|&(bom, encoding)| (encoding, bom.len()))
+    }
+
+    fn unit_size(self) -> usize {
+        match self {
+            TextEncoding::Utf16le | TextEncoding::Utf16be => 2,
+            TextEncoding::Utf32le | TextEncoding::Utf32be => 4,
+            TextEncoding::Auto | TextEncoding::Utf8 | TextEncoding::Latin1 => 1,
+        }
+    }
+
+    fn decode_unit(self, bytes: &[u8]) -> u32 {
+        match self {
+            TextEncoding::Utf16le => u16::from_le_bytes([bytes[0], bytes[1]]) as u32,
+            TextEncoding::Utf16be => u16::from_be_bytes([bytes[0], bytes[1]]) as u32,
+            TextEncoding::Utf32le => u32::from_le_bytes(bytes.try_into().unwrap()),
+            TextEncoding::Utf32be => u32::from_be_bytes(bytes.try_into().unwrap()),
+            TextEncoding::Auto | TextEncoding::Utf8 | TextEncoding::Latin1 => bytes[0] as u32,
+        }
+    }
+
+    /// Whether `text` can be written in this encoding.
+    pub fn can_encode(self, text: &str) -> bool {
+        self != TextEncoding::Latin1 || text.chars().all(|c| (c as u32) < 0x100)
+    }
+
+    /// Read the next line from `reader` and decode it, without its line
+    /// terminator. `None` at the end of the input.
+    pub fn read_line(self, reader: &mut dyn BufRead) -> anyhow::Result<Option<String>> {
+        let mut units = match self.read_units(reader)? {
+            Some(units) => units,
+            None => return Ok(None),
+        };
+        if units.last() == Some(&(b'\n' as u32)) {
+            units.pop();
+            if units.last() == Some(&(b'\r' as u32)) {
+                units.pop();
+            }
+        }
+
+        let line = match self {
+            TextEncoding::Auto | TextEncoding::Utf8 => {
+                let bytes = units.into_iter().map(|u| u as u8).collect();
+                String::from_utf8(bytes)
+                    .map_err(|err| anyhow::anyhow!("Input is not valid UTF-8: {}", err))?
+            }
+            TextEncoding::Latin1 => units.into_iter().map(|u| char::from(u as u8)).collect(),
+            TextEncoding::Utf16le | TextEncoding::Utf16be => {
+                char::decode_utf16(units.into_iter().map(|u| u as u16))
+                    .collect::<Result<String, _>>()
+                    .map_err(|err| anyhow::anyhow!("Input is not valid UTF-16: {}", err))?
+            }
+            TextEncoding::Utf32le | TextEncoding::Utf32be => units
+                .into_iter()
+                .map(|u| {
+                    char::from_u32(u).ok_or_else(|| {
+                        anyhow::anyhow!("Input is not valid UTF-32: invalid code point {:X}", u)
+                    })
+                })
+                .collect::<anyhow::Result<String>>()?,
+        };
+        Ok(Some(line))
+    }
+
+    /// The code units up to and including the next newline.
+    fn read_units(self, reader: &mut dyn BufRead) -> anyhow::Result<Option<Vec<u32>>> {
+        let size = self.unit_size();
+        if size == 1 {
+            let mut bytes = Vec::new();
+            if reader.read_until(b'\n', &mut bytes)? == 0 {
+                return Ok(None);
+            }
+            return Ok(Some(bytes.into_iter().map(u32::from).collect()));
+        }
+
+        let mut units = Vec::new();
+        let mut unit = [0u8; 4];
+        loop {
+            let mut filled = 0;
+            while filled < size {
+                match reader.read(&mut unit[filled..size])? {
+                    0 => break,
+                    n => filled += n,
+                }
+            }
+            if filled == 0 {
+                break;
+            }
+            anyhow::ensure!(
+                filled == size,
+                "Input ends in the middle of a {}-byte code unit",
+                size
+            );
+            let unit = self.decode_unit(&unit[..size]);
+            units.push(unit);
+            if unit == b'\n' as u32 {
+                break;
+            }
+        }
+        Ok(if units.is_empty() { None } else { Some(units) })
+    }
+
+    /// Add `text` to `buffer` in this encoding, with the bytes in `item` as
+    /// the item to shape and the rest as context.
+    ///
+    /// Latin-1 text must pass `can_encode`.
+    pub fn add_to_buffer(self, buffer: &mut Buffer, text: &str, item: Range<usize>) {
+        match self {
+            TextEncoding::Auto | TextEncoding::Utf8 => {
+                buffer.add_utf8(text, item.start, item.len())
+            }
+            TextEncoding::Utf16le | TextEncoding::Utf16be => {
+                let units: Vec<u16> = text.encode_utf16().collect();
+                let offset = text[..item.start].encode_utf16().count();
+                let length = text[item].encode_utf16().count();
+                buffer.add_utf16(&units, offset, length);
+            }
+            TextEncoding::Utf32le | TextEncoding::Utf32be => {
+                let units: Vec<u32> = text.chars().map(u32::from).collect();
+                let offset = text[..item.start].chars().count();
+                let length = text[item].chars().count();
+                buffer.add_utf32(&units, offset, length);
+            }
+            TextEncoding::Latin1 => {
+                let bytes: Vec<u8> = text.chars().map(|c| c as u32 as u8).collect();
+                let offset = text[..item.start].chars().count();
+                let length = text[item].chars().count();
+                buffer.add_latin1(&bytes, offset, length);
+            }
+        }
+    }
+
+    /// Byte offset into `text` of every code unit that cluster values count,
+    /// followed by `text.len()`. `None` if cluster values are byte offsets
+    /// already, with --utf8-clusters.
+    ///
+    /// Without --utf8-clusters, UTF-8 input counts characters.
+    pub fn cluster_offsets(self, text: &str, utf8_clusters: bool) -> Option<Vec<usize>> {
+        if utf8_clusters {
+            return None;
+        }
+        let utf16 = matches!(self, TextEncoding::Utf16le | TextEncoding::Utf16be);
+        let mut offsets = Vec::with_capacity(text.len() + 1);
+        for (i, c) in text.char_indices() {
+            let units = if utf16 { c.len_utf16() } else { 1 };
+            offsets.extend(std::iter::repeat(i).take(units));
+        }
+        offsets.push(text.len());
+        Some(offsets)
+    }
+}
+
+/// The byte offset into a text of `len` bytes that `cluster` stands for,
+/// given the `TextEncoding::cluster_offsets` of the text. Clamped to the end
+/// of the text.
+pub fn cluster_to_byte(offsets: Option<&[usize]>, len: usize, cluster: u32) -> usize {
+    match offsets {
+        Some(offsets) => offsets[(cluster as usize).min(offsets.len() - 1)],
+        None => (cluster as usize).min(len),
+    }
+}
+
+/// The cluster value of byte offset `offset`, the inverse of
+/// `cluster_to_byte`. Characters taking two UTF-16 code units have two equal
+/// offsets; the cluster is the first.
+pub fn byte_to_cluster(offsets: Option<&[usize]>, offset: usize) -> u32 {
+    match offsets {
+        Some(offsets) => offsets.partition_point(|&o| o < offset) as u32,
+        None => offset as u32,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn read_lines(encoding: TextEncoding, bytes: &[u8]) -> anyhow::Result<Vec<String>> {
+        let mut reader = Cursor::new(bytes);
+        let mut lines = Vec::new();
+        while let Some(line) = encoding.read_line(&mut reader)? {
+            lines.push(line);
+        }
+        Ok(lines)
+    }
+
+    #[test]
+    fn from_bom() {
+        assert_eq!(
+            TextEncoding::from_bom(b"\xEF\xBB\xBFa"),
+            Some((TextEncoding::Utf8, 3))
+        );
+        assert_eq!(
+            TextEncoding::from_bom(b"\xFF\xFEa\x00"),
+            Some((TextEncoding::Utf16le, 2))
+        );
+        assert_eq!(
+            TextEncoding::from_bom(b"\xFE\xFF\x00a"),
+            Some((TextEncoding::Utf16be, 2))
+        );
+        assert_eq!(
+            TextEncoding::from_bom(b"\xFF\xFE\x00\x00"),
+            Some((TextEncoding::Utf32le, 4))
+        );
+        assert_eq!(
+            TextEncoding::from_bom(b"\x00\x00\xFE\xFF"),
+            Some((TextEncoding::Utf32be, 4))
+        );
+        assert_eq!(TextEncoding::from_bom(b"abc"), None);
+        assert_eq!(TextEncoding::from_bom(b""), None);
+    }
+
+    #[test]
+    fn read_line_utf8() {
+        let lines = read_lines(TextEncoding::Utf8, b"a\r\nb\n\nc").unwrap();
+        assert_eq!(lines, ["a", "b", "", "c"]);
+        assert!(read_lines(TextEncoding::Utf8, b"\xFF\n").is_err());
+    }
+
+    #[test]
+    fn read_line_utf16() {
+        let text = "h\u{e9}\r\n\u{1F600}";
+        let le: Vec<u8> = text.encode_utf16().flat_map(u16::to_le_bytes).collect();
+        let be: Vec<u8> = text.encode_utf16().flat_map(u16::to_be_bytes).collect();
+        assert_eq!(
+            read_lines(TextEncoding::Utf16le, &le).unwrap(),
+            ["h\u{e9}", "\u{1F600}"]
+        );
+        assert_eq!(
+            read_lines(TextEncoding::Utf16be, &be).unwrap(),
+            ["h\u{e9}", "\u{1F600}"]
+        );
+        // A lone surrogate, and half a code unit.
+        assert!(read_lines(TextEncoding::Utf16le, b"\x00\xD8").is_err());
+        assert!(read_lines(TextEncoding::Utf16le, b"a\x00b").is_err());
+    }
+
+    #[test]
+    fn read_line_utf32() {
+        let text = "a\n\u{1F600}";
+        let le: Vec<u8> = text
+            .chars()
+            .flat_map(|c| (c as u32).to_le_bytes())
+            .collect();
+        let be: Vec<u8> = text
+            .chars()
+            .flat_map(|c| (c as u32).to_be_bytes())
+            .collect();
+        assert_eq!(
+            read_lines(TextEncoding::Utf32le, &le).unwrap(),
+            ["a", "\u{1F600}"]
+        );
+        assert_eq!(
+            read_lines(TextEncoding::Utf32be, &be).unwrap(),
+            ["a", "\u{1F600}"]
+        );
+        assert!(read_lines(TextEncoding::Utf32le, b"\x00\xD8\x00\x00").is_err());
+        assert!(read_lines(TextEncoding::Utf32le, b"a\x00\x00").is_err());
+    }
+
+    #[test]
+    fn read_line_latin1() {
+        let lines = read_lines(TextEncoding::Latin1, b"caf\xE9\nx").unwrap();
+        assert_eq!(lines, ["caf\u{e9}", "x"]);
+    }
+
+    #[test]
+    fn cluster_offsets() {
+        let text = "a\u{1F600}b";
+        assert_eq!(
+            TextEncoding::Utf8.cluster_offsets(text, false),
+            Some(vec![0, 1, 5, 6])
+        );
+        assert_eq!(
+            TextEncoding::Utf16le.cluster_offsets(text, false),
+            Some(vec![0, 1, 1, 5, 6])
+        );
+        assert_eq!(
+            TextEncoding::Utf32be.cluster_offsets(text, false),
+            Some(vec![0, 1, 5, 6])
+        );
+        assert_eq!(TextEncoding::Utf16le.cluster_offsets(text, true), None);
+    }
+
+    #[test]
+    fn cluster_to_byte_and_back() {
+        let text = "a\u{1F600}b";
+        let offsets = TextEncoding::Utf16le.cluster_offsets(text, false);
+        let offsets = offsets.as_deref();
+        assert_eq!(cluster_to_byte(offsets, text.len(), 2), 1);
+        assert_eq!(cluster_to_byte(offsets, text.len(), 3), 5);
+        assert_eq!(cluster_to_byte(offsets, text.len(), 10), 6);
+        assert_eq!(byte_to_cluster(offsets, 1), 1);
+        assert_eq!(byte_to_cluster(offsets, 5), 3);
+        assert_eq!(byte_to_cluster(offsets, 6), 4);
+
+        assert_eq!(cluster_to_byte(None, text.len(), 5), 5);
+        assert_eq!(cluster_to_byte(None, text.len(), 10), 6);
+        assert_eq!(byte_to_cluster(None, 5), 5);
+    }
+}
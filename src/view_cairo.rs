@@ -2,60 +2,107 @@ use std::mem::MaybeUninit;
 
 use harfbuzz_sys as ffi;
 
+use crate::font_fallback::FontRun;
+use crate::hb::Buffer;
 use crate::helper_cairo::{
-    create_cairo_context, create_scaled_font, HelperCairoLine, ScaledFontExt,
+    create_cairo_context, create_scaled_font, create_scaled_font_for, destroy_cairo_context,
+    parse_color, HelperCairoLine, ScaledFontExt,
 };
-use crate::options::{FontExtents, Options};
+use crate::line_wrap::wrap_lines;
+use crate::options::{FontExtents, FontOpts, Options, TextOpts};
 use crate::output::Output;
-
-const SUBPIXEL_BITS: i32 = 6;
+use crate::text_encoding::TextEncoding;
 
 pub struct ViewCairo {
     scale_bits: i32,
+    text_encoding: TextEncoding,
     direction: ffi::hb_direction_t,
     lines: Vec<HelperCairoLine>,
 }
 
 impl Output for ViewCairo {
     type Opts = Options;
-    fn create(_buffer: *mut ffi::hb_buffer_t, _opts: &Options) -> ViewCairo {
-        ViewCairo {
-            scale_bits: SUBPIXEL_BITS,
+    fn create(_buffer: &Buffer, opts: &Options) -> anyhow::Result<ViewCairo> {
+        // Colors are only used once all lines are shaped; check them first.
+        parse_color(&opts.view.background)?;
+        parse_color(&opts.view.foreground)?;
+        Ok(ViewCairo {
+            scale_bits: -Options::SUBPIXEL_BITS,
+            text_encoding: opts.text_encoding(),
             direction: ffi::HB_DIRECTION_INVALID,
             lines: Vec::new(),
-        }
+        })
     }
 
-    fn new_line(&self) {}
-    unsafe fn consume_text(
+    fn new_line(&mut self) {}
+    fn consume_text(
         &mut self,
-        _buffer: *mut ffi::hb_buffer_t,
+        _buffer: &Buffer,
         _text: &str,
         _utf8_clusters: bool,
-    ) {
+    ) -> anyhow::Result<()> {
+        Ok(())
     }
-    unsafe fn consume_glyphs(
+    fn consume_glyphs(
         &mut self,
-        buffer: *mut ffi::hb_buffer_t,
+        buffer: &Buffer,
         text: &str,
         utf8_clusters: bool,
-    ) {
-        self.direction = ffi::hb_buffer_get_direction(buffer);
-        let l = HelperCairoLine::from_buffer(buffer, text, self.scale_bits, utf8_clusters);
-        self.lines.push(l);
+        font_runs: &[FontRun],
+        opts: &Options,
+    ) -> anyhow::Result<()> {
+        self.direction = buffer.direction();
+        let wrap_width = match opts.view.wrap_width {
+            Some(wrap_width) => wrap_width,
+            None => {
+                self.push_line(buffer, text, utf8_clusters, font_runs);
+                return Ok(());
+            }
+        };
+
+        let font_size = opts.font_opts.font_size();
+        let font_size = if crate::hb_direction_is_vertical(self.direction) {
+            font_size.y
+        } else {
+            font_size.x
+        };
+        let max_width = libm::scalbn(wrap_width.to_pixels(font_size as f64), -self.scale_bits);
+        for line in wrap_lines(opts, buffer, text, font_runs, max_width as i64)? {
+            let line_text = &text[line.text];
+            self.push_line(&line.buffer, line_text, utf8_clusters, &line.font_runs);
+        }
+        Ok(())
     }
-    unsafe fn finish(&mut self, _buffer: *mut ffi::hb_buffer_t, opts: &Options) {
-        self.render(opts).unwrap();
+    fn finish(&mut self, _buffer: &Buffer, opts: &Options) -> anyhow::Result<()> {
+        self.render(opts)
     }
 }
 
 impl ViewCairo {
-    unsafe fn render(&self, opts: &Options) -> anyhow::Result<()> {
+    fn push_line(
+        &mut self,
+        buffer: &Buffer,
+        text: &str,
+        utf8_clusters: bool,
+        font_runs: &[FontRun],
+    ) {
+        let cluster_offsets = self.text_encoding.cluster_offsets(text, utf8_clusters);
+        let l = HelperCairoLine::from_buffer(
+            buffer,
+            text,
+            self.scale_bits,
+            cluster_offsets.as_deref(),
+            font_runs,
+        );
+        self.lines.push(l);
+    }
+
+    fn render(&self, opts: &Options) -> anyhow::Result<()> {
         let is_vertical = crate::hb_direction_is_vertical(self.direction);
         let vert = if is_vertical { 1. } else { 0. };
         let horiz = if is_vertical { 0. } else { 1. };
 
-        let font_size = opts.font_opts.font_size.unwrap_or_default();
+        let font_size = opts.font_opts.font_size();
 
         let x_sign = if font_size.x < 0. { -1. } else { 1. };
         let y_sign = if font_size.y < 0. { -1. } else { 1. };
@@ -65,9 +112,15 @@ impl ViewCairo {
         let font_extents = if let Some(extents) = opts.view.font_extents {
             extents
         } else {
-            let mut hb_extents = MaybeUninit::zeroed();
-            ffi::hb_font_get_extents_for_direction(font, self.direction, hb_extents.as_mut_ptr());
-            let hb_extents = hb_extents.assume_init();
+            let hb_extents = unsafe {
+                let mut hb_extents = MaybeUninit::zeroed();
+                ffi::hb_font_get_extents_for_direction(
+                    font.as_ptr(),
+                    self.direction,
+                    hb_extents.as_mut_ptr(),
+                );
+                hb_extents.assume_init()
+            };
             FontExtents {
                 ascent: libm::scalbn(hb_extents.ascender as _, self.scale_bits),
                 descent: -libm::scalbn(hb_extents.descender as _, self.scale_bits),
@@ -102,7 +155,27 @@ impl ViewCairo {
 
         let scaled_font = create_scaled_font(&opts.font_opts)?;
 
-        let content = if scaled_font.has_color() {
+        // Scaled fonts of the main font and every fallback font in use.
+        let mut scaled_fonts = vec![(font.as_ptr(), scaled_font.clone())];
+        for run in self.lines.iter().flat_map(|l| l.font_runs.iter()) {
+            let run_font = run.font.as_ptr();
+            if !scaled_fonts.iter().any(|(font, _)| *font == run_font) {
+                let scaled_font = create_scaled_font_for(&opts.font_opts, &run.font)?;
+                scaled_fonts.push((run_font, scaled_font));
+            }
+        }
+        let scaled_font_for = |run_font: *mut ffi::hb_font_t| {
+            scaled_fonts
+                .iter()
+                .find(|(font, _)| *font == run_font)
+                .map(|(_, scaled_font)| scaled_font.clone())
+                .unwrap_or_else(|| scaled_font.clone())
+        };
+
+        let content = if scaled_fonts
+            .iter()
+            .any(|(_, scaled_font)| scaled_font.has_color())
+        {
             cairo::Content::Color
         } else {
             cairo::Content::Alpha
@@ -123,10 +196,10 @@ impl ViewCairo {
         cr.translate(margin.l, margin.t);
 
         if is_vertical {
-            cr.translate(w - ascent, y_sign.clamp(0., h));
+            cr.translate(w - ascent, if y_sign < 0. { h } else { 0. });
         } else {
             cr.translate(
-                x_sign.clamp(0., w),
+                if x_sign < 0. { w } else { 0. },
                 if y_sign < 0. { descent } else { ascent },
             );
         }
@@ -135,21 +208,134 @@ impl ViewCairo {
         cr.translate(vert * leading, -horiz * leading);
         for l in self.lines.iter() {
             cr.translate(-vert * leading, horiz * leading);
+            let run_fonts: Vec<_> = l
+                .font_runs
+                .iter()
+                .map(|run| (run.start..run.end, scaled_font_for(run.font.as_ptr())))
+                .collect();
             if opts.view.annotate {
-                todo!()
+                annotate_line(&cr, l, &run_fonts, ascent, descent, is_vertical)?;
             }
 
+            let glyphs = &l.glyphs[..l.num_glyphs];
+            if run_fonts.len() > 1 {
+                // Cairo can't switch fonts within a text cluster mapping, so
+                // each font's glyphs are shown on their own.
+                for (range, run_font) in run_fonts.iter() {
+                    cr.set_scaled_font(run_font);
+                    cr.show_glyphs(&glyphs[range.clone()])?;
+                }
+                cr.set_scaled_font(&scaled_font);
+                continue;
+            }
+            if let Some((_, run_font)) = run_fonts.first() {
+                cr.set_scaled_font(run_font);
+            }
             if false && cr.target().type_() == cairo::SurfaceType::Image {
                 // cairo_show_glyphs dosen't supported subpixel positioning
-                cr.glyph_path(&l.glyphs);
-                cr.fill().unwrap();
+                cr.glyph_path(glyphs);
+                cr.fill()?;
             } else if !l.text_clusters.is_empty() {
-                cr.show_text_glyphs(&l.utf8, &l.glyphs, &l.text_clusters, l.cluster_flags)
-                    .unwrap();
+                cr.show_text_glyphs(&l.utf8, glyphs, &l.text_clusters, l.cluster_flags)?;
             } else {
-                cr.show_glyphs(&l.glyphs).unwrap();
+                cr.show_glyphs(glyphs)?;
             }
         }
-        Ok(())
+
+        destroy_cairo_context(cr)
+    }
+}
+
+/// Draw advance boxes, ink extents, cluster boundaries and glyph origins of
+/// `l` on top of where its glyphs will be shown.
+fn annotate_line(
+    cr: &cairo::Context,
+    l: &HelperCairoLine,
+    run_fonts: &[(std::ops::Range<usize>, cairo::ScaledFont)],
+    ascent: f64,
+    descent: f64,
+    is_vertical: bool,
+) -> anyhow::Result<()> {
+    cr.save()?;
+
+    // Pen positions before each glyph, plus the end of the line.
+    let mut pens = Vec::with_capacity(l.num_glyphs + 1);
+    let (mut pen_x, mut pen_y) = (0., 0.);
+    pens.push((pen_x, pen_y));
+    for &(x_advance, y_advance) in l.advances.iter() {
+        pen_x += x_advance;
+        pen_y += y_advance;
+        pens.push((pen_x, pen_y));
+    }
+
+    // The line box across the text direction.
+    let (cross_start, cross_end) = if is_vertical {
+        (-descent, ascent)
+    } else {
+        (-ascent, descent)
+    };
+
+    // Advance boxes
+    cr.set_source_rgba(0., 0., 1., 0.5);
+    cr.set_line_width(1.);
+    for (i, &(x_advance, y_advance)) in l.advances.iter().enumerate() {
+        let (x, y) = pens[i];
+        if is_vertical {
+            cr.rectangle(x + cross_start, y, cross_end - cross_start, y_advance);
+        } else {
+            cr.rectangle(x, y + cross_start, x_advance, cross_end - cross_start);
+        }
+    }
+    cr.stroke()?;
+
+    // Ink extents
+    cr.set_source_rgba(1., 0., 1., 0.5);
+    for (range, scaled_font) in run_fonts.iter() {
+        for glyph in l.glyphs[range.clone()].iter() {
+            let extents = scaled_font.glyph_extents(&[cairo::Glyph::new(glyph.index(), 0., 0.)]);
+            if extents.width() == 0. && extents.height() == 0. {
+                continue;
+            }
+            cr.rectangle(
+                glyph.x() + extents.x_bearing(),
+                glyph.y() + extents.y_bearing(),
+                extents.width(),
+                extents.height(),
+            );
+        }
     }
+    cr.stroke()?;
+
+    // Cluster boundaries
+    cr.set_source_rgba(0., 0.6, 0., 0.7);
+    cr.set_line_width(2.);
+    for i in 0..=l.num_glyphs {
+        let is_boundary =
+            i == 0 || i == l.num_glyphs || l.glyph_clusters[i] != l.glyph_clusters[i - 1];
+        if !is_boundary {
+            continue;
+        }
+        let (x, y) = pens[i];
+        if is_vertical {
+            cr.move_to(x + cross_start, y);
+            cr.line_to(x + cross_end, y);
+        } else {
+            cr.move_to(x, y + cross_start);
+            cr.line_to(x, y + cross_end);
+        }
+    }
+    cr.stroke()?;
+
+    // Actual glyph origins
+    cr.set_source_rgba(1., 0., 0., 0.5);
+    cr.set_line_width(5.);
+    cr.set_line_cap(cairo::LineCap::Round);
+    for glyph in l.glyphs[..l.num_glyphs].iter() {
+        cr.move_to(glyph.x(), glyph.y());
+        cr.rel_line_to(0., 0.);
+    }
+    cr.stroke()?;
+
+    cr.restore()?;
+    Ok(())
 }